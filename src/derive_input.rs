@@ -0,0 +1,245 @@
+//! Preprocessing of custom derive input, the way rustc does it before invoking the derive.
+//!
+//! Rustc removes `#[derive]` attributes from the item, expands `#[cfg_attr]` and removes fields
+//! and variants disabled by `#[cfg]`; `#[cfg]` attributes of the enabled ones are removed too. Macros may rely on that, e.g. by rejecting unknown
+//! attributes, so without preprocessing their expansion would differ from the compiler's.
+
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::parse::{ParseStream, Parser};
+use syn::punctuated::Punctuated;
+use syn::token::Comma;
+use syn::{Attribute, Data, DeriveInput, Field, Fields, Lit, Meta, NestedMeta, Path};
+
+use ExpansionError;
+
+/// Set of enabled cfg options, like `unix` or `feature = "std"`.
+#[derive(Debug, Clone, Default)]
+pub struct CfgSet {
+    options: Vec<(String, Option<String>)>,
+}
+
+impl CfgSet {
+    /// Parses options in the format of rustc `--cfg` flag: `name` or `name="value"`.
+    pub fn parse<S: AsRef<str>>(options: &[S]) -> Result<CfgSet, ExpansionError> {
+        let mut parsed = vec![];
+
+        for option in options {
+            let option = option.as_ref();
+            let invalid_option = || ExpansionError::InvalidTask {
+                message: format!("invalid cfg option '{}'", option),
+            };
+
+            let (name, value) = match option.find('=') {
+                None => (option.trim(), None),
+
+                Some(eq) => {
+                    let value = option[eq + 1..].trim();
+                    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+                        return Err(invalid_option());
+                    }
+
+                    (option[..eq].trim(), Some(value[1..value.len() - 1].to_string()))
+                }
+            };
+
+            if name.is_empty() {
+                return Err(invalid_option());
+            }
+
+            parsed.push((name.to_string(), value));
+        }
+
+        Ok(CfgSet { options: parsed })
+    }
+
+    fn is_enabled(&self, name: &str, value: Option<&str>) -> bool {
+        self.options
+            .iter()
+            .any(|(n, v)| n == name && v.as_ref().map(|v| v.as_str()) == value)
+    }
+
+    fn eval(&self, predicate: &NestedMeta) -> syn::Result<bool> {
+        let meta = match predicate {
+            NestedMeta::Meta(meta) => meta,
+            NestedMeta::Lit(lit) => return Err(syn::Error::new_spanned(lit, "expected cfg predicate")),
+        };
+
+        match meta {
+            Meta::Path(path) => Ok(self.is_enabled(&single_name(path)?, None)),
+
+            Meta::NameValue(name_value) => match &name_value.lit {
+                Lit::Str(value) => Ok(self.is_enabled(&single_name(&name_value.path)?, Some(&value.value()))),
+                lit => Err(syn::Error::new_spanned(lit, "cfg value should be a string")),
+            },
+
+            Meta::List(list) => {
+                let args = list.nested.iter();
+
+                match single_name(&list.path)?.as_str() {
+                    "all" => {
+                        for arg in args {
+                            if !self.eval(arg)? {
+                                return Ok(false);
+                            }
+                        }
+                        Ok(true)
+                    }
+
+                    "any" => {
+                        for arg in args {
+                            if self.eval(arg)? {
+                                return Ok(true);
+                            }
+                        }
+                        Ok(false)
+                    }
+
+                    "not" if list.nested.len() == 1 => Ok(!self.eval(&list.nested[0])?),
+
+                    _ => Err(syn::Error::new_spanned(list, "invalid cfg predicate")),
+                }
+            }
+        }
+    }
+
+    /// Whether the item with `attrs` is enabled, i.e. all of its `#[cfg]` predicates hold.
+    ///
+    /// `#[cfg]` attributes are removed from `attrs`, since they are not needed anymore.
+    fn configure(&self, attrs: &mut Vec<Attribute>) -> syn::Result<bool> {
+        for attr in attrs.iter().filter(|attr| attr.path.is_ident("cfg")) {
+            let predicate = attr.parse_args::<NestedMeta>()?;
+            if !self.eval(&predicate)? {
+                return Ok(false);
+            }
+        }
+
+        attrs.retain(|attr| !attr.path.is_ident("cfg"));
+        Ok(true)
+    }
+
+    /// Replaces each `#[cfg_attr(predicate, attrs...)]` with `attrs` if the predicate holds.
+    fn expand_cfg_attrs(&self, attrs: Vec<Attribute>) -> syn::Result<Vec<Attribute>> {
+        let mut expanded = vec![];
+
+        for attr in attrs {
+            if !attr.path.is_ident("cfg_attr") {
+                expanded.push(attr);
+                continue;
+            }
+
+            let (predicate, nested) = attr.parse_args_with(|input: ParseStream| {
+                let predicate: NestedMeta = input.parse()?;
+                input.parse::<Comma>()?;
+                let nested = Punctuated::<TokenStream, Comma>::parse_terminated_with(
+                    input,
+                    parse_until_comma,
+                )?;
+
+                Ok((predicate, nested))
+            })?;
+
+            if !self.eval(&predicate)? {
+                continue;
+            }
+
+            let mut nested_attrs = vec![];
+            for tokens in nested {
+                let (path, tokens) = (|input: ParseStream| {
+                    let path = input.call(Path::parse_mod_style)?;
+                    let tokens: TokenStream = input.parse()?;
+                    Ok((path, tokens))
+                }).parse2(tokens)?;
+
+                nested_attrs.push(Attribute {
+                    path,
+                    tokens,
+                    ..attr.clone()
+                });
+            }
+
+            // Expanded attributes may be `cfg_attr` themselves
+            expanded.extend(self.expand_cfg_attrs(nested_attrs)?);
+        }
+
+        Ok(expanded)
+    }
+
+    fn configure_fields(&self, fields: &mut Fields) -> syn::Result<()> {
+        match fields {
+            Fields::Named(named) => self.configure_field_list(&mut named.named),
+            Fields::Unnamed(unnamed) => self.configure_field_list(&mut unnamed.unnamed),
+            Fields::Unit => Ok(()),
+        }
+    }
+
+    fn configure_field_list(&self, fields: &mut Punctuated<Field, Comma>) -> syn::Result<()> {
+        let mut configured = Punctuated::new();
+        for mut field in std::mem::replace(fields, Punctuated::new()) {
+            field.attrs = self.expand_cfg_attrs(field.attrs)?;
+            if self.configure(&mut field.attrs)? {
+                configured.push(field);
+            }
+        }
+
+        *fields = configured;
+        Ok(())
+    }
+}
+
+fn single_name(path: &Path) -> syn::Result<String> {
+    path.get_ident()
+        .map(|ident| ident.to_string())
+        .ok_or_else(|| syn::Error::new_spanned(path, "cfg option should be a single identifier"))
+}
+
+fn parse_until_comma(input: ParseStream) -> syn::Result<TokenStream> {
+    let mut tokens = TokenStream::new();
+
+    while !input.is_empty() && !input.peek(Comma) {
+        tokens.extend(Some(input.parse::<TokenTree>()?));
+    }
+
+    Ok(tokens)
+}
+
+/// Preprocesses the input of a custom derive like rustc does, evaluating `cfg` against `cfg_set`.
+///
+/// Returns `None` if the item itself is disabled by `#[cfg]`, so the derive should not run.
+pub fn preprocess(input: TokenStream, cfg_set: &CfgSet) -> Result<Option<TokenStream>, ExpansionError> {
+    let source = input.to_string();
+    let to_error = |e: syn::Error| ExpansionError::Parse {
+        input: source.clone(),
+        message: e.to_string(),
+    };
+
+    let mut item: DeriveInput = syn::parse2(input).map_err(&to_error)?;
+
+    item.attrs = cfg_set.expand_cfg_attrs(item.attrs).map_err(&to_error)?;
+    if !cfg_set.configure(&mut item.attrs).map_err(&to_error)? {
+        return Ok(None);
+    }
+
+    item.attrs.retain(|attr| !attr.path.is_ident("derive"));
+
+    match &mut item.data {
+        Data::Struct(data) => cfg_set.configure_fields(&mut data.fields).map_err(&to_error)?,
+
+        Data::Enum(data) => {
+            let mut configured = Punctuated::new();
+            for mut variant in std::mem::replace(&mut data.variants, Punctuated::new()) {
+                variant.attrs = cfg_set.expand_cfg_attrs(variant.attrs).map_err(&to_error)?;
+                if cfg_set.configure(&mut variant.attrs).map_err(&to_error)? {
+                    cfg_set.configure_fields(&mut variant.fields).map_err(&to_error)?;
+                    configured.push(variant);
+                }
+            }
+
+            data.variants = configured;
+        }
+
+        Data::Union(data) => cfg_set.configure_field_list(&mut data.fields.named).map_err(&to_error)?,
+    }
+
+    Ok(Some(item.into_token_stream()))
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use macro_expansion::ErrorCode;
+
+/// Everything that can go wrong while performing a single expansion task.
+#[derive(Debug)]
+pub enum ExpansionError {
+    /// Task is valid json, but it cannot be performed as requested.
+    InvalidTask { message: String },
+
+    /// Macro body or attributes are not a valid token stream.
+    Parse { input: String, message: String },
+
+    /// Library file does not exist or cannot be accessed.
+    LibraryNotFound { path: PathBuf, message: String },
+
+    /// Library file exists, but cannot be loaded as a dynamic library.
+    LibraryLoad { path: PathBuf, message: String },
+
+    /// Library does not export proc macro registrar symbol, so it is not a proc macro library.
+    RegistrarSymbolNotFound { path: PathBuf },
+
+    /// Registrar symbol is exported, but cannot be loaded from the library.
+    SymbolLoad { path: PathBuf, message: String },
+
+    /// None of the libraries exports a macro with such name.
+    MacroNotFound { name: String },
+
+    /// Macro has panicked during the expansion.
+    ///
+    /// `bridge_method` is the entry point of the macro (`ProcMacro::CustomDerive`, etc.).
+    MacroPanic {
+        name: String,
+        bridge_method: String,
+        message: String,
+    },
+
+    /// Expander itself has panicked in the `bridge_method` while serving the macro.
+    ServerPanic {
+        name: String,
+        bridge_method: String,
+        message: String,
+    },
+
+    /// Macro has called the `bridge_method` with arguments which cannot be served, e.g. has
+    /// tried to create a literal from an invalid text.
+    InvalidBridgeCall {
+        name: String,
+        bridge_method: String,
+        message: String,
+    },
+
+    /// Worker process performing the expansion has crashed or exited.
+    WorkerCrashed { message: String },
+
+    /// Macro has not finished in time.
+    Timeout { name: String, timeout: Duration },
+
+    /// Worker has exceeded its address space limit.
+    MemoryLimitExceeded { limit_mb: u64 },
+
+    /// Worker has exceeded its CPU time limit.
+    CpuLimitExceeded { limit_seconds: u64 },
+
+    /// Macro has failed to open a file because of the file descriptors limit.
+    OpenFilesLimitExceeded { limit: u64, message: String },
+}
+
+impl ExpansionError {
+    /// Machine-readable code of the error, reported in the `ExpansionResult::Error`.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ExpansionError::InvalidTask { .. } => ErrorCode::InvalidRequest,
+            ExpansionError::Parse { .. } => ErrorCode::ParseError,
+            ExpansionError::LibraryNotFound { .. } => ErrorCode::LibraryNotFound,
+            ExpansionError::LibraryLoad { .. } => ErrorCode::LibraryLoadFailed,
+            ExpansionError::RegistrarSymbolNotFound { .. } => ErrorCode::RegistrarSymbolNotFound,
+            ExpansionError::SymbolLoad { .. } => ErrorCode::SymbolLoadFailed,
+            ExpansionError::MacroNotFound { .. } => ErrorCode::MacroNotFound,
+            ExpansionError::MacroPanic { .. } => ErrorCode::MacroPanicked,
+            ExpansionError::ServerPanic { .. } => ErrorCode::ServerPanicked,
+            ExpansionError::InvalidBridgeCall { .. } => ErrorCode::InvalidBridgeCall,
+            ExpansionError::WorkerCrashed { .. } => ErrorCode::WorkerCrashed,
+            ExpansionError::Timeout { .. } => ErrorCode::Timeout,
+            ExpansionError::MemoryLimitExceeded { .. } => ErrorCode::MemoryLimitExceeded,
+            ExpansionError::CpuLimitExceeded { .. } => ErrorCode::CpuLimitExceeded,
+            ExpansionError::OpenFilesLimitExceeded { .. } => ErrorCode::OpenFilesLimitExceeded,
+        }
+    }
+
+    /// Name of the bridge method involved in the panic, if the error is caused by one.
+    pub fn bridge_method(&self) -> Option<&str> {
+        match self {
+            ExpansionError::MacroPanic { bridge_method, .. }
+            | ExpansionError::ServerPanic { bridge_method, .. }
+            | ExpansionError::InvalidBridgeCall { bridge_method, .. } => Some(bridge_method),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExpansionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpansionError::InvalidTask { message } => write!(f, "Invalid task: {}", message),
+            ExpansionError::Parse { input, message } => {
+                write!(f, "Error while parsing this code: '{}': {}", input, message)
+            }
+            ExpansionError::LibraryNotFound { path, message } => {
+                write!(f, "Cannot find library {:?}: {}", path, message)
+            }
+            ExpansionError::LibraryLoad { path, message } => {
+                write!(f, "Cannot load library {:?}: {}", path, message)
+            }
+            ExpansionError::RegistrarSymbolNotFound { path } => {
+                write!(f, "Cannot find registrar symbol in file {:?}", path)
+            }
+            ExpansionError::SymbolLoad { path, message } => {
+                write!(f, "Cannot load registrar symbol from file {:?}: {}", path, message)
+            }
+            ExpansionError::MacroNotFound { name } => {
+                write!(f, "Cannot find macro '{}' in provided libraries", name)
+            }
+            ExpansionError::MacroPanic { name, message, .. } => {
+                write!(f, "Macro '{}' has panicked: {}", name, message)
+            }
+            ExpansionError::ServerPanic {
+                name,
+                bridge_method,
+                message,
+            } => write!(
+                f,
+                "Expander has panicked in {} while expanding '{}': {}",
+                bridge_method, name, message
+            ),
+            ExpansionError::InvalidBridgeCall {
+                name,
+                bridge_method,
+                message,
+            } => write!(
+                f,
+                "Macro '{}' has called {} with invalid arguments: {}",
+                name, bridge_method, message
+            ),
+            ExpansionError::WorkerCrashed { message } => write!(f, "Worker has crashed: {}", message),
+            ExpansionError::Timeout { name, timeout } => {
+                write!(f, "Macro '{}' has not finished in {:?}", name, timeout)
+            }
+            ExpansionError::MemoryLimitExceeded { limit_mb } => {
+                write!(f, "Worker has exceeded memory limit of {} MB", limit_mb)
+            }
+            ExpansionError::CpuLimitExceeded { limit_seconds } => {
+                write!(f, "Worker has exceeded CPU time limit of {} s", limit_seconds)
+            }
+            ExpansionError::OpenFilesLimitExceeded { limit, message } => write!(
+                f,
+                "Macro has exceeded limit of {} open files: {}",
+                limit, message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExpansionError {}
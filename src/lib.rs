@@ -5,32 +5,134 @@
 extern crate sharedlib;
 extern crate libloading;
 extern crate goblin;
+#[cfg(unix)]
+extern crate libc;
 extern crate proc_macro;
+extern crate proc_macro2;
+extern crate quote;
 #[macro_use]
 extern crate serde_derive;
+extern crate syn;
 
 //use dylib::DynamicLibrary;
+use derive_input::CfgSet;
 use goblin::mach::Mach;
 use goblin::Object;
-use macro_expansion::{ExpansionResult, ExpansionTask};
+use macro_expansion::{
+    Diagnostic, ExpansionResult, ExpansionTask, ExportedMacro, InputKind, MacroExpansion,
+    MacroKind, SourceLocation, TokenSpan,
+};
+use proc_macro::bridge::PanicMessage;
 use proc_macro::bridge::client::ProcMacro;
 use proc_macro::bridge::server::SameThread;
 use std::fs::File;
+use std::any::Any;
 use std::io::Read;
-use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use sharedlib::{Lib, Data, Symbol};
+use source_map::SourceMap;
 use libloading::Library;
 
 pub mod macro_expansion;
+mod derive_input;
+mod error;
+mod library_registry;
+mod resource_limits;
 mod rustc_server;
+mod source_map;
+mod span_map;
+mod supervisor;
+
+pub use error::ExpansionError;
+pub use library_registry::LibraryRegistry;
+pub use resource_limits::ProcessLimiter;
+pub use supervisor::Supervisor;
 
 static NEW_REGISTRAR_SYMBOL: &str = "__rustc_proc_macro_decls_";
 static _OLD_REGISTRAR_SYMBOL: &str = "__rustc_derive_registrar_";
 
 const EXEC_STRATEGY: SameThread = SameThread;
 
-fn parse_string(code: &str) -> Option<proc_macro2::TokenStream> {
-    syn::parse_str(code).ok()
+/// Stack size of the expansion threads, the same as the one of the main thread, since macros
+/// are often deeply recursive and the default stack of spawned threads is much smaller.
+const EXPANSION_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+fn parse_string(code: &str) -> Result<proc_macro2::TokenStream, ExpansionError> {
+    syn::parse_str(code).map_err(|e| ExpansionError::Parse {
+        input: code.to_string(),
+        message: e.to_string(),
+    })
+}
+
+fn panic_message(msg: &PanicMessage) -> String {
+    msg.as_str().unwrap_or("<non-string panic payload>").to_string()
+}
+
+fn panic_payload_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&'static str>() {
+        return msg.to_string();
+    }
+
+    if let Some(msg) = payload.downcast_ref::<String>() {
+        return msg.clone();
+    }
+
+    "<non-string panic payload>".to_string()
+}
+
+/// Runs the macro client, turning any panic into an error.
+///
+/// Panics inside the macro are caught by the bridge itself, but panics of our server may also
+/// escape the client; both are caught here. `entry_point` is reported as the bridge method
+/// when the panic has not happened inside of our server.
+fn run_client<F>(
+    macro_name: &str,
+    entry_point: &str,
+    run: F,
+) -> Result<rustc_server::TokenStream, ExpansionError>
+where
+    F: FnOnce() -> Result<rustc_server::TokenStream, PanicMessage>,
+{
+    rustc_server::take_panicked_method();
+    rustc_server::take_invalid_call();
+
+    let result = panic::catch_unwind(AssertUnwindSafe(run));
+    let panicked_method = rustc_server::take_panicked_method();
+    let invalid_call = rustc_server::take_invalid_call();
+
+    let message = match result {
+        Ok(Ok(token_stream)) => return Ok(token_stream),
+        Ok(Err(msg)) => panic_message(&msg),
+        Err(payload) => panic_payload_message(&*payload),
+    };
+
+    let error = match panicked_method {
+        Some(method) if invalid_call => ExpansionError::InvalidBridgeCall {
+            name: macro_name.to_string(),
+            bridge_method: method.to_string(),
+            message,
+        },
+
+        Some(method) => ExpansionError::ServerPanic {
+            name: macro_name.to_string(),
+            bridge_method: method.to_string(),
+            message,
+        },
+
+        None => ExpansionError::MacroPanic {
+            name: macro_name.to_string(),
+            bridge_method: entry_point.to_string(),
+            message,
+        },
+    };
+
+    Err(error)
 }
 
 fn read_bytes(file: &Path) -> Option<Vec<u8>> {
@@ -116,25 +218,33 @@ fn load_library(file: &Path) -> Result<Library, std::io::Error> {
 }
 
 struct ProcMacroLibraryLibloading {
+    path: PathBuf,
     lib: Library,
     exported_macros: Vec<ProcMacro>,
 }
 
 impl ProcMacroLibraryLibloading {
-    fn open(file: &Path) -> Result<Self, String> {
+    fn open(file: &Path) -> Result<Self, ExpansionError> {
         let symbol_name = find_registrar_symbol(file)
-            .ok_or(format!("Cannot find registrar symbol in file {:?}", file))?;
+            .ok_or(ExpansionError::RegistrarSymbolNotFound { path: file.to_path_buf() })?;
 
-        let lib = load_library(file).map_err(|e| e.to_string())?;
+        let lib = load_library(file).map_err(|e| ExpansionError::LibraryLoad {
+            path: file.to_path_buf(),
+            message: e.to_string(),
+        })?;
 
         let exported_macros = {
             let macros: libloading::Symbol<&&[ProcMacro]> = unsafe { lib.get(symbol_name.as_bytes()) }
-                .map_err(|e| e.to_string())?;
+                .map_err(|e| ExpansionError::SymbolLoad {
+                    path: file.to_path_buf(),
+                    message: e.to_string(),
+                })?;
 
             macros.to_vec()
         };
 
         Ok(ProcMacroLibraryLibloading {
+            path: file.to_path_buf(),
             lib,
             exported_macros,
         })
@@ -142,26 +252,34 @@ impl ProcMacroLibraryLibloading {
 }
 
 struct ProcMacroLibrarySharedLib {
+    path: PathBuf,
     lib: Lib,
     exported_macros: Vec<ProcMacro>,
 }
 
 impl ProcMacroLibrarySharedLib {
-    fn open(file: &Path) -> Result<Self, String> {
+    fn open(file: &Path) -> Result<Self, ExpansionError> {
         let symbol_name = find_registrar_symbol(file)
-            .ok_or(format!("Cannot find registrar symbol in file {:?}", file))?;
+            .ok_or(ExpansionError::RegistrarSymbolNotFound { path: file.to_path_buf() })?;
 
-        let lib = unsafe { Lib::new(file) }.map_err(|e| e.to_string())?;
+        let lib = unsafe { Lib::new(file) }.map_err(|e| ExpansionError::LibraryLoad {
+            path: file.to_path_buf(),
+            message: e.to_string(),
+        })?;
 
         let exported_macros = {
             // data already implies reference
             let macros: Data<&[ProcMacro]> = unsafe { lib.find_data(&symbol_name) }
-                .map_err(|e| e.to_string())?;
+                .map_err(|e| ExpansionError::SymbolLoad {
+                    path: file.to_path_buf(),
+                    message: e.to_string(),
+                })?;
 
             unsafe { *macros.get() }.to_vec()
         };
 
         Ok(ProcMacroLibrarySharedLib {
+            path: file.to_path_buf(),
             lib,
             exported_macros,
         })
@@ -199,24 +317,29 @@ impl ProcMacroLibrarySharedLib {
 
 type ProcMacroLibraryImpl = ProcMacroLibraryLibloading;
 
+fn canonicalize_library_path(file: &Path) -> Result<PathBuf, ExpansionError> {
+    file.canonicalize().map_err(|e| ExpansionError::LibraryNotFound {
+        path: file.to_path_buf(),
+        message: e.to_string(),
+    })
+}
+
 pub struct Expander {
-    libs: Vec<ProcMacroLibraryImpl>,
+    libs: Vec<Arc<ProcMacroLibraryImpl>>,
 }
 
 impl Expander {
-    pub fn new<P: AsRef<Path>>(libs_paths: &[P]) -> Result<Expander, String> {
+    pub fn new<P: AsRef<Path>>(libs_paths: &[P]) -> Result<Expander, ExpansionError> {
         let mut libs = vec![];
 
         for lib in libs_paths {
             /* Some libraries for dynamic loading require canonicalized path (even when it is
             already absolute
             */
-            let lib = lib.as_ref().canonicalize().expect(
-                &format!("Cannot canonicalize {:?}", lib.as_ref())
-            );
+            let lib = canonicalize_library_path(lib.as_ref())?;
 
             let library = ProcMacroLibraryImpl::open(&lib)?;
-            libs.push(library)
+            libs.push(Arc::new(library))
         }
 
         Ok(Expander { libs })
@@ -227,84 +350,464 @@ impl Expander {
         macro_name: &str,
         macro_body: &str,
         attributes: Option<&String>,
-    ) -> Result<String, proc_macro::bridge::PanicMessage> {
-        let parsed_body = parse_string(macro_body).expect(
-            &format!("Error while parsing this code: '{}'", macro_body)
-        );
+        timeout: Option<Duration>,
+    ) -> Result<String, ExpansionError> {
+        let input = MacroInput {
+            body: macro_body.to_string(),
+            attributes: attributes.cloned(),
+            ..Default::default()
+        };
 
-        let parsed_attributes = attributes.map_or(proc_macro2::TokenStream::new(), |attr| {
-            parse_string(attr).expect(
-                &format!("Error while parsing this code: '{}'", attr)
-            )
-        });
+        self.expand_macro(macro_name, &input, None, timeout, &mut vec![])
+            .map(|expansion| expansion.expansion)
+    }
+
+    /// Expands each of the macros on the same input, in order; fails if any of them fails.
+    ///
+    /// This is how all the derives of `#[derive(A, B, C)]` are expanded. `timeout` is applied
+    /// to each macro separately.
+    ///
+    /// Diagnostics emitted by the macros are added to `diagnostics`, even if the expansion fails.
+    pub fn expand_all<S: AsRef<str>>(
+        &self,
+        macro_names: &[S],
+        input: &MacroInput,
+        timeout: Option<Duration>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<Vec<MacroExpansion>, ExpansionError> {
+        let cfg_set = match &input.cfg {
+            Some(cfg) => Some(CfgSet::parse(cfg)?),
+            None => None,
+        };
+
+        macro_names
+            .iter()
+            .map(|name| self.expand_macro(name.as_ref(), input, cfg_set.as_ref(), timeout, diagnostics))
+            .collect()
+    }
 
+    fn expand_macro(
+        &self,
+        macro_name: &str,
+        input: &MacroInput,
+        cfg_set: Option<&CfgSet>,
+        timeout: Option<Duration>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<MacroExpansion, ExpansionError> {
+        let (lib, proc_macro) = self.find_macro(macro_name).ok_or(ExpansionError::MacroNotFound {
+            name: macro_name.to_string(),
+        })?;
+
+        let library = lib.path.clone();
+
+        let input = input.clone();
+        let cfg_set = cfg_set.cloned();
+
+        let (expansion, emitted) = run_on_thread(macro_name, timeout, move || {
+            // Library should stay loaded as long as the macro is running, even after the timeout
+            let _lib = lib;
+            Ok(expand_macro(proc_macro, &input, cfg_set.as_ref()))
+        })?;
+
+        diagnostics.extend(emitted);
+        let (expansion, tokens) = expansion?;
+
+        Ok(MacroExpansion {
+            macro_name: macro_name.to_string(),
+            library,
+            expansion,
+            tokens,
+        })
+    }
+
+    /// Macros exported by the libraries of the expander, in order of the libraries.
+    pub fn macros(&self) -> Vec<ExportedMacro> {
+        self.libs
+            .iter()
+            .flat_map(|lib| lib.exported_macros.iter().map(move |m| exported_macro(&lib.path, m)))
+            .collect()
+    }
+
+    /// Finds a macro which would be used to expand `macro_name`.
+    pub fn find(&self, macro_name: &str) -> Option<ExportedMacro> {
+        self.find_macro(macro_name)
+            .map(|(lib, proc_macro)| exported_macro(&lib.path, &proc_macro))
+    }
+
+    fn find_macro(&self, macro_name: &str) -> Option<(Arc<ProcMacroLibraryImpl>, ProcMacro)> {
         for lib in &self.libs {
             for proc_macro in &lib.exported_macros {
-                match proc_macro {
-                    ProcMacro::CustomDerive {
-                        trait_name, client, ..
-                    } if *trait_name == macro_name => {
-                        let res = client.run(
-                            &EXEC_STRATEGY,
-                            rustc_server::Rustc::default(),
-                            parsed_body,
-                        );
-
-                        return res.map(|token_stream| token_stream.to_string());
-                    }
-
-                    ProcMacro::Bang { name, client } if *name == macro_name => {
-                        let res = client.run(
-                            &EXEC_STRATEGY,
-                            rustc_server::Rustc::default(),
-                            parsed_body,
-                        );
-
-                        return res.map(|token_stream| token_stream.to_string());
-                    }
-
-                    ProcMacro::Attr { name, client } if *name == macro_name => {
-                        let res = client.run(
-                            &EXEC_STRATEGY,
-                            rustc_server::Rustc::default(),
-                            parsed_attributes,
-                            parsed_body,
-                        );
-
-                        return res.map(|token_stream| token_stream.to_string());
-                    }
-
-                    _ => {
-                        continue;
-                    }
+                if proc_macro_name(proc_macro) == macro_name {
+                    return Some((lib.clone(), *proc_macro));
                 }
             }
         }
 
-        Err(proc_macro::bridge::PanicMessage::String(
-            "Nothing to expand".to_string(),
-        ))
+        None
     }
 }
 
-pub fn expand_task(task: &ExpansionTask) -> ExpansionResult {
-    let expander = Expander::new(&task.libs).expect(
-        &format!("Cannot expand with provided libraries: ${:?}", &task.libs)
-    );
+fn proc_macro_name(proc_macro: &ProcMacro) -> &'static str {
+    match proc_macro {
+        ProcMacro::CustomDerive { trait_name, .. } => trait_name,
+        ProcMacro::Bang { name, .. } | ProcMacro::Attr { name, .. } => name,
+    }
+}
+
+fn exported_macro(library: &Path, proc_macro: &ProcMacro) -> ExportedMacro {
+    let (kind, attributes) = match proc_macro {
+        ProcMacro::CustomDerive { attributes, .. } => (MacroKind::CustomDerive, *attributes),
+        ProcMacro::Attr { .. } => (MacroKind::Attr, &[][..]),
+        ProcMacro::Bang { .. } => (MacroKind::Bang, &[][..]),
+    };
+
+    ExportedMacro {
+        kind,
+        name: proc_macro_name(proc_macro).to_string(),
+        attributes: attributes.iter().map(|attr| attr.to_string()).collect(),
+        library: library.to_path_buf(),
+    }
+}
 
-    let result = match expander.expand(&task.macro_name, &task.macro_body, task.attributes.as_ref()) {
-        Ok(expansion) => ExpansionResult::Success { expansion },
+/// Lists macros exported by the libraries, in order of the libraries.
+pub fn list_macros<P: AsRef<Path>>(libs_paths: &[P]) -> Result<Vec<ExportedMacro>, ExpansionError> {
+    Ok(Expander::new(libs_paths)?.macros())
+}
 
-        Err(msg) => {
-            let reason = format!(
-                "Cannot perform expansion for {}: error {:?}!",
-                &task.macro_name,
-                msg.as_str()
-            );
+/// Input of the macros of a task, the same for all of them.
+#[derive(Debug, Clone, Default)]
+pub struct MacroInput {
+    pub body: String,
+    pub attributes: Option<String>,
+    /// See `ExpansionTask::cfg`.
+    pub cfg: Option<Vec<String>>,
+    /// See `ExpansionTask::source`.
+    pub source: Option<SourceLocation>,
+    /// See `ExpansionTask::attributes_source`.
+    pub attributes_source: Option<SourceLocation>,
+    /// See `ExpansionTask::span_map`.
+    pub span_map: bool,
+    /// See `ExpansionTask::compile_errors`.
+    pub compile_errors: bool,
+}
 
-            ExpansionResult::Error { reason }
+impl<'a> From<&'a ExpansionTask> for MacroInput {
+    fn from(task: &'a ExpansionTask) -> MacroInput {
+        MacroInput {
+            body: task.macro_body.clone(),
+            attributes: task.attributes.clone(),
+            cfg: task.cfg.clone(),
+            source: task.source.clone(),
+            attributes_source: task.attributes_source.clone(),
+            span_map: task.span_map,
+            compile_errors: task.compile_errors,
         }
+    }
+}
+
+/// Expansion and the origins of its tokens (if requested).
+type MacroOutput = (String, Option<Vec<TokenSpan>>);
+
+/// Expands the macro, returning the diagnostics it has emitted along with the result.
+fn expand_macro(
+    proc_macro: ProcMacro,
+    input: &MacroInput,
+    cfg_set: Option<&CfgSet>,
+) -> (Result<MacroOutput, ExpansionError>, Vec<Diagnostic>) {
+    let mut diagnostics = vec![];
+    let output = run_macro(proc_macro, input, cfg_set, &mut diagnostics);
+    (output, diagnostics)
+}
+
+fn run_macro(
+    proc_macro: ProcMacro,
+    input: &MacroInput,
+    cfg_set: Option<&CfgSet>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<MacroOutput, ExpansionError> {
+    let macro_name = proc_macro_name(&proc_macro);
+    let mut parsed_body = parse_string(&input.body)?;
+
+    if let (ProcMacro::CustomDerive { .. }, Some(cfg_set)) = (&proc_macro, cfg_set) {
+        parsed_body = match derive_input::preprocess(parsed_body, cfg_set)? {
+            Some(item) => item,
+            // Item is disabled by `#[cfg]`, so rustc would not invoke the derive at all
+            None => {
+                let tokens = if input.span_map { Some(vec![]) } else { None };
+                return Ok((String::new(), tokens));
+            }
+        };
+    }
+
+    let parsed_attributes = match &input.attributes {
+        Some(attr) => Some(parse_string(attr)?),
+        None => None,
+    };
+
+    let mut source_map = SourceMap::new();
+    let body_file = source_map.add(InputKind::MacroBody, &input.body, input.source.as_ref());
+    let attributes_file = input
+        .attributes
+        .as_ref()
+        .map(|attr| source_map.add(InputKind::Attributes, attr, input.attributes_source.as_ref()));
+
+    let source_map = Rc::new(source_map);
+    let server = rustc_server::Rustc::new(source_map.clone(), body_file);
+    let emitted = server.diagnostics();
+    let body = server.token_stream(parsed_body, body_file);
+    let attributes = match (parsed_attributes, attributes_file) {
+        (Some(attr), Some(file)) => server.token_stream(attr, file),
+        _ => rustc_server::TokenStream::new(),
     };
 
-    result
+    let expansion = match proc_macro {
+        ProcMacro::CustomDerive { client, .. } => {
+            run_client(macro_name, "ProcMacro::CustomDerive", || {
+                client.run(&EXEC_STRATEGY, server, body)
+            })
+        }
+
+        ProcMacro::Bang { client, .. } => run_client(macro_name, "ProcMacro::Bang", || {
+            client.run(&EXEC_STRATEGY, server, body)
+        }),
+
+        ProcMacro::Attr { client, .. } => run_client(macro_name, "ProcMacro::Attr", || {
+            client.run(&EXEC_STRATEGY, server, attributes, body)
+        }),
+    };
+
+    diagnostics.extend(
+        emitted
+            .borrow()
+            .iter()
+            .map(|diagnostic| span_map::diagnostic(diagnostic, &source_map)),
+    );
+    let expansion = expansion?;
+
+    if input.compile_errors {
+        diagnostics.extend(
+            expansion
+                .compile_errors()
+                .iter()
+                .map(|diagnostic| span_map::diagnostic(diagnostic, &source_map)),
+        );
+    }
+
+    if !input.span_map {
+        return Ok((expansion.to_string(&source_map), None));
+    }
+
+    let (text, tokens) = span_map::print_with_spans(&expansion, &source_map);
+    Ok((text, Some(tokens)))
+}
+
+/// Runs the expansion on a separate thread and stops waiting for it after the `timeout`, if any.
+///
+/// There is no way to stop a thread, so a macro which is stuck keeps running in the background.
+/// It does not block other expansions, though; isolated workers are restarted in that case.
+///
+/// Without a timeout the expansion still gets its own thread: proc_macro2 keeps the texts of all
+/// parsed inputs in a thread local for their spans, and they are freed only with the thread.
+fn run_on_thread<T, F>(macro_name: &str, timeout: Option<Duration>, expansion: F) -> Result<T, ExpansionError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ExpansionError> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+
+    thread::Builder::new()
+        .name(format!("expansion of {}", macro_name))
+        .stack_size(EXPANSION_STACK_SIZE)
+        .spawn(move || {
+            let _ = sender.send(expansion());
+        })
+        .map_err(|e| ExpansionError::ServerPanic {
+            name: macro_name.to_string(),
+            bridge_method: "<expansion thread>".to_string(),
+            message: format!("cannot spawn expansion thread: {}", e),
+        })?;
+
+    let received = match timeout {
+        Some(timeout) => receiver.recv_timeout(timeout),
+        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    };
+
+    match received {
+        Ok(result) => result,
+
+        Err(RecvTimeoutError::Timeout) => Err(ExpansionError::Timeout {
+            name: macro_name.to_string(),
+            timeout: timeout.unwrap_or_default(),
+        }),
+
+        Err(RecvTimeoutError::Disconnected) => Err(ExpansionError::ServerPanic {
+            name: macro_name.to_string(),
+            bridge_method: "<expansion thread>".to_string(),
+            message: "expansion thread has exited without a result".to_string(),
+        }),
+    }
+}
+
+pub fn expand_task(task: &ExpansionTask) -> ExpansionResult {
+    expand_task_with_registry(&mut LibraryRegistry::new(), task)
+}
+
+/// Same as `expand_task`, but takes libraries from the `registry` instead of opening them anew.
+pub fn expand_task_with_registry(registry: &mut LibraryRegistry, task: &ExpansionTask) -> ExpansionResult {
+    if !task.limits.is_empty() {
+        let message = "resource limits are supported only by isolated workers".to_string();
+        return error_result(task, ExpansionError::InvalidTask { message });
+    }
+
+    let mut diagnostics = vec![];
+    let expansion = expand_with_registry(registry, task, &mut diagnostics);
+    to_result(task, expansion, diagnostics)
+}
+
+/// Same as `expand_task_with_registry`, but applies `task.limits` to the current process first.
+///
+/// Since limits affect the whole process, this is supposed to be used only by isolated workers.
+pub fn expand_task_with_limits(
+    registry: &mut LibraryRegistry,
+    limiter: &ProcessLimiter,
+    task: &ExpansionTask,
+) -> ExpansionResult {
+    let mut diagnostics = vec![];
+    let expansion = limiter
+        .apply(&task.limits)
+        .map_err(|e| ExpansionError::InvalidTask {
+            message: format!("cannot apply resource limits: {}", e),
+        })
+        .and_then(|()| expand_with_registry(registry, task, &mut diagnostics))
+        .map_err(|err| resource_limits::check_open_files(err, &task.limits));
+
+    to_result(task, expansion, diagnostics)
+}
+
+/// Takes the stdout of the process for the protocol output, redirecting file descriptor 1
+/// to stderr.
+///
+/// Macros are free to print whatever they want, which must not get mixed with the json
+/// responses. The returned file is not inherited by child processes.
+#[cfg(unix)]
+pub fn isolate_stdout() -> std::io::Result<File> {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    std::io::stdout().flush()?;
+
+    let protocol_fd = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_DUPFD_CLOEXEC, 0) };
+    if protocol_fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        let err = std::io::Error::last_os_error();
+        unsafe { libc::close(protocol_fd) };
+        return Err(err);
+    }
+
+    Ok(unsafe { File::from_raw_fd(protocol_fd) })
+}
+
+#[cfg(not(unix))]
+pub fn isolate_stdout() -> std::io::Result<File> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "stdout cannot be isolated on this platform",
+    ))
+}
+
+fn expand_with_registry(
+    registry: &mut LibraryRegistry,
+    task: &ExpansionTask,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<Vec<MacroExpansion>, ExpansionError> {
+    if task.macro_name.names().is_empty() {
+        let message = "no macro names are given".to_string();
+        return Err(ExpansionError::InvalidTask { message });
+    }
+
+    registry.expander(&task.libs).and_then(|expander| {
+        expander.expand_all(
+            task.macro_name.names(),
+            &MacroInput::from(task),
+            task.timeout_ms.map(Duration::from_millis),
+            diagnostics,
+        )
+    })
+}
+
+fn to_result(
+    task: &ExpansionTask,
+    expansion: Result<Vec<MacroExpansion>, ExpansionError>,
+    diagnostics: Vec<Diagnostic>,
+) -> ExpansionResult {
+    match expansion {
+        Ok(parts) => ExpansionResult::Success {
+            id: task.id.clone(),
+            expansion: parts
+                .iter()
+                .map(|part| part.expansion.as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            parts,
+            diagnostics,
+        },
+
+        Err(err) => error_result_with_diagnostics(task, err, diagnostics),
+    }
+}
+
+fn error_result(task: &ExpansionTask, err: ExpansionError) -> ExpansionResult {
+    error_result_with_diagnostics(task, err, vec![])
+}
+
+fn error_result_with_diagnostics(
+    task: &ExpansionTask,
+    err: ExpansionError,
+    diagnostics: Vec<Diagnostic>,
+) -> ExpansionResult {
+    ExpansionResult::Error {
+        id: task.id.clone(),
+        code: err.code(),
+        reason: format!("Cannot perform expansion for {}: {}", &task.macro_name, err),
+        bridge_method: err.bridge_method().map(|method| method.to_string()),
+        diagnostics,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_server::BridgeMethodGuard;
+
+    #[test]
+    fn test_server_panics_are_reported_with_the_method() {
+        let result = run_client("test_macro", "ProcMacro::Bang", || {
+            let _guard = BridgeMethodGuard::enter("Span::start");
+            panic!("server has failed")
+        });
+
+        match result.err() {
+            Some(ExpansionError::ServerPanic { ref bridge_method, ref message, .. }) => {
+                assert_eq!(bridge_method, "Span::start");
+                assert_eq!(message, "server has failed");
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_macro_panics_are_reported_with_the_entry_point() {
+        let result = run_client("test_macro", "ProcMacro::Bang", || panic!("macro has failed"));
+
+        match result.err() {
+            Some(ExpansionError::MacroPanic { ref bridge_method, ref message, .. }) => {
+                assert_eq!(bridge_method, "ProcMacro::Bang");
+                assert_eq!(message, "macro has failed");
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
 }
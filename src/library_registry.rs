@@ -0,0 +1,187 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use {canonicalize_library_path, read_bytes, ExpansionError, Expander, ProcMacroLibraryImpl};
+
+/// Cheap to obtain file properties, checked on every lookup.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl FileStamp {
+    fn of(file: &Path) -> Result<FileStamp, ExpansionError> {
+        let metadata = file.metadata().map_err(|e| ExpansionError::LibraryNotFound {
+            path: file.to_path_buf(),
+            message: e.to_string(),
+        })?;
+
+        Ok(FileStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        })
+    }
+}
+
+fn content_hash(file: &Path) -> Result<u64, ExpansionError> {
+    let bytes = read_bytes(file).ok_or(ExpansionError::LibraryNotFound {
+        path: file.to_path_buf(),
+        message: "cannot read library file".to_string(),
+    })?;
+
+    let mut hasher = DefaultHasher::new();
+    hasher.write(&bytes);
+    Ok(hasher.finish())
+}
+
+static NEXT_COPY_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Private copy of a library file, which is removed when it is not needed anymore.
+///
+/// Libraries are opened from such copies because the dynamic loader identifies libraries by
+/// their path and file, so a rebuilt library opened from the original path may be served
+/// from the old mapping. Copies are named after the content hash, and are unique per process
+/// and per load.
+///
+/// Copies are hidden files next to the original library, since the temporary directory may be
+/// mounted without permission to execute the files.
+struct LibraryCopy {
+    path: PathBuf,
+}
+
+impl LibraryCopy {
+    fn create(file: &Path, content_hash: u64) -> Result<LibraryCopy, ExpansionError> {
+        let file_name = file.file_name().and_then(|name| name.to_str()).unwrap_or("library");
+        let path = file.with_file_name(format!(
+            ".proc_macro_expander-{}-{}-{:016x}-{}",
+            std::process::id(),
+            NEXT_COPY_ID.fetch_add(1, Ordering::SeqCst),
+            content_hash,
+            file_name
+        ));
+
+        fs::copy(file, &path).map_err(|e| ExpansionError::LibraryLoad {
+            path: file.to_path_buf(),
+            message: format!("cannot copy library to {}: {}", path.display(), e),
+        })?;
+
+        Ok(LibraryCopy { path })
+    }
+
+    /// Removes the copy of the opened library on unix, where the library stays mapped without
+    /// its file. This way the copy is not left behind even if the process crashes.
+    fn opened(&self) {
+        if cfg!(unix) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Drop for LibraryCopy {
+    fn drop(&mut self) {
+        // File is left if the library is still in use on other platforms
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+struct CachedLibrary {
+    stamp: FileStamp,
+    content_hash: u64,
+    library: Arc<ProcMacroLibraryImpl>,
+    _copy: LibraryCopy,
+}
+
+/// Keeps opened proc macro libraries alive between expansion tasks.
+///
+/// Libraries are keyed by their canonical path. Library is opened again only when its file
+/// has changed: stamp (mtime and size) is checked on every lookup, and if it differs, content
+/// hash decides whether the library has to be reloaded. Each load opens a fresh copy of the
+/// file, see `LibraryCopy`.
+#[derive(Default)]
+pub struct LibraryRegistry {
+    libs: HashMap<PathBuf, CachedLibrary>,
+}
+
+impl LibraryRegistry {
+    pub fn new() -> LibraryRegistry {
+        LibraryRegistry::default()
+    }
+
+    /// Creates an expander for the libraries, reusing already opened ones.
+    pub fn expander<P: AsRef<Path>>(&mut self, libs_paths: &[P]) -> Result<Expander, ExpansionError> {
+        let mut libs = vec![];
+
+        for lib in libs_paths {
+            libs.push(self.open(lib.as_ref())?);
+        }
+
+        Ok(Expander { libs })
+    }
+
+    fn open(&mut self, file: &Path) -> Result<Arc<ProcMacroLibraryImpl>, ExpansionError> {
+        let file = canonicalize_library_path(file)?;
+
+        let stamp = FileStamp::of(&file)?;
+
+        let known_hash = match self.libs.get_mut(&file) {
+            Some(ref cached) if cached.stamp == stamp => {
+                return Ok(cached.library.clone());
+            }
+
+            Some(cached) => Some(cached.content_hash),
+
+            None => None,
+        };
+
+        let content_hash = content_hash(&file)?;
+
+        if known_hash == Some(content_hash) {
+            let cached = self.libs.get_mut(&file).expect("Should be consistent");
+            cached.stamp = stamp;
+            return Ok(cached.library.clone());
+        }
+
+        // Stale library is dropped before opening the new one, so it can be actually unloaded
+        // if nobody else is using it
+        self.libs.remove(&file);
+
+        let copy = LibraryCopy::create(&file, content_hash)?;
+        let mut library = ProcMacroLibraryImpl::open(&copy.path).map_err(|err| match err {
+            ExpansionError::LibraryLoad { message, .. } => ExpansionError::LibraryLoad {
+                path: file.clone(),
+                message,
+            },
+            ExpansionError::RegistrarSymbolNotFound { .. } => {
+                ExpansionError::RegistrarSymbolNotFound { path: file.clone() }
+            }
+            ExpansionError::SymbolLoad { message, .. } => ExpansionError::SymbolLoad {
+                path: file.clone(),
+                message,
+            },
+            err => err,
+        })?;
+        copy.opened();
+        // Macros are reported with the library path of the task, not with the path of the copy
+        library.path = file.clone();
+
+        let library = Arc::new(library);
+        self.libs.insert(
+            file,
+            CachedLibrary {
+                stamp,
+                content_hash,
+                library: library.clone(),
+                _copy: copy,
+            },
+        );
+
+        Ok(library)
+    }
+}
@@ -1,7 +1,14 @@
 use std::path::PathBuf;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ExpansionTask {
+    /// Optional caller-supplied identifier of the task.
+    ///
+    /// It is echoed back in the `ExpansionResult` produced for this task, so results can be
+    /// matched with tasks without relying on their order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+
     /// Argument of macro call.
     ///
     /// In custom derive that would be a struct or enum; in attribute-like macro - underlying
@@ -10,21 +17,396 @@ pub struct ExpansionTask {
 
     /// Names of macros to expand.
     ///
-    /// In custom derive those are names of derived traits (`Serialize`, `Getters`, etc.). In
-    /// attribute-like and functiona-like macros - single name of macro itself (`show_streams`).
-    pub macro_name: String,
+    /// In custom derive those are names of derived traits (`Serialize`, `Getters`, etc.), either
+    /// a single one or a list of them. In attribute-like and functiona-like macros - single name
+    /// of macro itself (`show_streams`).
+    pub macro_name: MacroNames,
 
     /// Possible attributes for the attribute-like macros.
     pub attributes: Option<String>,
 
     pub libs: Vec<PathBuf>,
+
+    /// Location of `macro_body` in the user's file.
+    ///
+    /// If set, spans of the input tokens point to their real positions in that file (see
+    /// `Span::source_file`, `Span::start` and `Span::end`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SourceLocation>,
+
+    /// Location of `attributes` in the user's file, the same as `source` is for `macro_body`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes_source: Option<SourceLocation>,
+
+    /// Report where each token of the expansion comes from (see `MacroExpansion::tokens`).
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub span_map: bool,
+
+    /// Report top-level `compile_error!("...")` invocations of the expansions as error
+    /// diagnostics (see `ExpansionResult::Success::diagnostics`).
+    ///
+    /// Expansions themselves are reported unchanged.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub compile_errors: bool,
+
+    /// Enabled cfg options, in the format of rustc `--cfg` flag (`unix`, `feature="std"`).
+    ///
+    /// If set, input of custom derives is preprocessed the way rustc does it: `#[derive]`
+    /// attributes are removed, `#[cfg_attr]` are expanded, and fields and variants disabled by
+    /// `#[cfg]` are removed. Otherwise `macro_body` is passed to the macro as is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cfg: Option<Vec<String>>,
+
+    /// Maximum time the macro is allowed to run, in milliseconds.
+    ///
+    /// Overrides the global `--timeout-ms` option. No timeout if neither is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+
+    /// Limits for the worker performing the expansion.
+    ///
+    /// Each limit overrides the corresponding global option. Limits are supported only in
+    /// isolated workers (`--isolate`).
+    #[serde(default, skip_serializing_if = "ResourceLimits::is_empty")]
+    pub limits: ResourceLimits,
+}
+
+/// Position of the start of a macro input in the user's file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceLocation {
+    pub file: PathBuf,
+
+    /// Offset in bytes, starting from 0.
+    #[serde(default)]
+    pub offset: usize,
+
+    /// Line, starting from 1.
+    #[serde(default = "first_line")]
+    pub line: usize,
+
+    /// Column in chars, starting from 0.
+    #[serde(default)]
+    pub column: usize,
+}
+
+fn first_line() -> usize {
+    1
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Either a single macro name or a list of them, like all derives of `#[derive(A, B, C)]`.
+///
+/// Listed macros are expanded on the same `macro_body` one after another.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MacroNames {
+    Single(String),
+    List(Vec<String>),
+}
+
+impl MacroNames {
+    pub fn names(&self) -> &[String] {
+        match self {
+            MacroNames::Single(name) => std::slice::from_ref(name),
+            MacroNames::List(names) => names,
+        }
+    }
+}
+
+impl Default for MacroNames {
+    fn default() -> MacroNames {
+        MacroNames::List(vec![])
+    }
+}
+
+impl From<String> for MacroNames {
+    fn from(name: String) -> MacroNames {
+        MacroNames::Single(name)
+    }
+}
+
+impl<'a> From<&'a str> for MacroNames {
+    fn from(name: &'a str) -> MacroNames {
+        MacroNames::Single(name.to_string())
+    }
+}
+
+impl From<Vec<String>> for MacroNames {
+    fn from(names: Vec<String>) -> MacroNames {
+        MacroNames::List(names)
+    }
+}
+
+impl std::fmt::Display for MacroNames {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.names().join(", "))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    /// Maximum size of the address space of the worker, in megabytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<u64>,
+
+    /// Maximum CPU time the expansion may take, in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<u64>,
+
+    /// Maximum number of file descriptors the worker may have open.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_files: Option<u64>,
 }
 
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+
+    /// Takes limits which are not set in `self` from `defaults`.
+    pub fn or(&self, defaults: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            memory_mb: self.memory_mb.or(defaults.memory_mb),
+            cpu_seconds: self.cpu_seconds.or(defaults.cpu_seconds),
+            open_files: self.open_files.or(defaults.open_files),
+        }
+    }
+}
+
+/// Kind of a procedural macro, as declared by `#[proc_macro_derive]`, `#[proc_macro_attribute]`
+/// or `#[proc_macro]`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MacroKind {
+    CustomDerive,
+    Attr,
+    Bang,
+}
+
+/// Macro exported by a proc macro library.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedMacro {
+    pub kind: MacroKind,
+
+    /// Name of the macro; for custom derives that is the name of derived trait.
+    pub name: String,
+
+    /// Helper attributes declared by custom derive (`#[proc_macro_derive(Foo, attributes(foo))]`).
+    ///
+    /// Always empty for other kinds of macros.
+    #[serde(default)]
+    pub attributes: Vec<String>,
+
+    /// Canonical path of the library which exports the macro.
+    pub library: PathBuf,
+}
+
+impl ExportedMacro {
+    /// Whether `attribute` (like `serde` in `#[serde(rename = "...")]`) may be used on items
+    /// this macro is applied to.
+    pub fn has_helper_attribute(&self, attribute: &str) -> bool {
+        self.attributes.iter().any(|attr| attr == attribute)
+    }
+}
+
+/// Result of a single `ExpansionTask`.
+///
+/// Every variant carries the `id` of the task it was produced for (if the task had one).
+/// Any new kind of response should do the same, so clients can match responses to requests
+/// even when they arrive out of order.
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ExpansionResult {
     #[serde(rename = "success")]
-    Success { expansion: String },
+    Success {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        /// Expansions of all the macros of the task, one after another.
+        expansion: String,
+        /// Expansion of each macro separately, in order of `ExpansionTask::macro_name`.
+        #[serde(default)]
+        parts: Vec<MacroExpansion>,
+        /// Diagnostics emitted by the macros.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        diagnostics: Vec<Diagnostic>,
+    },
     #[serde(rename = "error")]
-    Error { reason: String },
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        code: ErrorCode,
+        reason: String,
+        /// Bridge method involved, if the error is caused by a panic.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bridge_method: Option<String>,
+        /// Diagnostics emitted by the macros before the failure.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        diagnostics: Vec<Diagnostic>,
+    },
+}
+
+/// Expansion of a single macro of the task.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MacroExpansion {
+    pub macro_name: String,
+
+    /// Canonical path of the library which has provided the macro.
+    pub library: PathBuf,
+
+    pub expansion: String,
+
+    /// Tokens of the `expansion` with their origin, if `ExpansionTask::span_map` is set.
+    ///
+    /// Delimiters of groups are reported as separate tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<Vec<TokenSpan>>,
+}
+
+/// Single token of an expansion.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenSpan {
+    /// Byte range of the token in the expansion.
+    pub start: usize,
+    pub end: usize,
+
+    pub source: TokenSource,
+    pub hygiene: Hygiene,
+}
+
+/// Where the span of a token comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum TokenSource {
+    /// Span of a token of the macro input, which was either passed through or reused by the macro.
+    ///
+    /// Range is in bytes, in the user's file if the `ExpansionTask::source` is known, and in
+    /// the input text itself otherwise.
+    #[serde(rename = "input")]
+    Input {
+        input: InputKind,
+        start: usize,
+        end: usize,
+    },
+    /// Span created by the macro with `Span::call_site` (or created implicitly, e.g. by parsing
+    /// a string).
+    #[serde(rename = "call_site")]
+    CallSite,
+    /// Span created by the macro with `Span::def_site`.
+    #[serde(rename = "def_site")]
+    DefSite,
+    /// Span created by the macro with `Span::mixed_site`.
+    #[serde(rename = "mixed_site")]
+    MixedSite,
+}
+
+/// Where the identifiers of a token are resolved.
+///
+/// Tokens of the macro input are resolved at the call site, tokens created by the macro
+/// get the hygiene of their span (which may be changed with `Span::resolved_at`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Hygiene {
+    /// As if the identifier was written by the user at the macro call site.
+    #[serde(rename = "call_site")]
+    CallSite,
+    /// At the macro definition site, invisible to the user's code.
+    #[serde(rename = "def_site")]
+    DefSite,
+    /// `macro_rules` hygiene: local variables, labels and `$crate` are resolved at the
+    /// definition site, everything else at the call site.
+    #[serde(rename = "mixed_site")]
+    MixedSite,
+}
+
+/// Diagnostic emitted by a macro with `proc_macro::Diagnostic::emit`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub level: DiagnosticLevel,
+    pub message: String,
+    pub spans: Vec<TokenSource>,
+    /// Notes and help messages attached to the diagnostic.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Diagnostic>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiagnosticLevel {
+    #[serde(rename = "error")]
+    Error,
+    #[serde(rename = "warning")]
+    Warning,
+    #[serde(rename = "note")]
+    Note,
+    #[serde(rename = "help")]
+    Help,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputKind {
+    #[serde(rename = "macro_body")]
+    MacroBody,
+    #[serde(rename = "attributes")]
+    Attributes,
+}
+
+/// Machine-readable kind of the `ExpansionResult::Error`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// Request or task is not a valid json of the expected shape.
+    #[serde(rename = "invalid_request")]
+    InvalidRequest,
+    #[serde(rename = "parse_error")]
+    ParseError,
+    #[serde(rename = "library_not_found")]
+    LibraryNotFound,
+    #[serde(rename = "library_load_failed")]
+    LibraryLoadFailed,
+    #[serde(rename = "registrar_symbol_not_found")]
+    RegistrarSymbolNotFound,
+    #[serde(rename = "symbol_load_failed")]
+    SymbolLoadFailed,
+    #[serde(rename = "macro_not_found")]
+    MacroNotFound,
+    #[serde(rename = "macro_panicked")]
+    MacroPanicked,
+    /// Expander itself has panicked while serving the macro, e.g. in an unsupported method.
+    #[serde(rename = "server_panicked")]
+    ServerPanicked,
+    /// Macro has called a bridge method with invalid arguments, e.g. with an invalid literal.
+    #[serde(rename = "invalid_bridge_call")]
+    InvalidBridgeCall,
+    /// Worker process has crashed or exited while performing the expansion.
+    #[serde(rename = "worker_crashed")]
+    WorkerCrashed,
+    /// Macro has not finished in the time given by `timeout_ms`.
+    #[serde(rename = "timeout")]
+    Timeout,
+    #[serde(rename = "memory_limit_exceeded")]
+    MemoryLimitExceeded,
+    #[serde(rename = "cpu_limit_exceeded")]
+    CpuLimitExceeded,
+    #[serde(rename = "open_files_limit_exceeded")]
+    OpenFilesLimitExceeded,
+}
+
+impl ExpansionResult {
+    /// Identifier of the task this result was produced for.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            ExpansionResult::Success { id, .. } | ExpansionResult::Error { id, .. } => {
+                id.as_ref().map(|id| id.as_str())
+            }
+        }
+    }
+}
+
+/// Single line of input in the server mode (`proc_macro_expander --server`).
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ExpansionRequest {
+    #[serde(rename = "expand")]
+    Expand(ExpansionTask),
+    #[serde(rename = "shutdown")]
+    Shutdown,
 }
@@ -3,9 +3,13 @@
 #![feature(proc_macro_diagnostic)]
 extern crate proc_macro_expander;
 
-use std::io::Read;
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
 
-use proc_macro_expander::macro_expansion::{ExpansionTask, ExpansionResult};
+use proc_macro_expander::macro_expansion::{
+    ErrorCode, ExpansionRequest, ExpansionResult, ExpansionTask, ResourceLimits,
+};
+use proc_macro_expander::{LibraryRegistry, ProcessLimiter, Supervisor};
 
 fn read_stdin() -> String {
     let mut buff = String::new();
@@ -16,18 +20,223 @@ fn read_stdin() -> String {
     buff
 }
 
-fn main() {
+enum Backend {
+    InProcess(LibraryRegistry),
+    /// Worker of an isolated expander, applies limits of the tasks to itself.
+    Worker(LibraryRegistry, ProcessLimiter),
+    Isolated(Supervisor),
+}
+
+/// Performs expansion tasks either in the current process, or in isolated worker processes.
+struct Executor {
+    backend: Backend,
+    default_timeout_ms: Option<u64>,
+    default_limits: ResourceLimits,
+}
+
+impl Executor {
+    fn new(options: &Options) -> Executor {
+        let backend = if options.worker {
+            let limiter = ProcessLimiter::new().expect("Cannot read resource limits!");
+            Backend::Worker(LibraryRegistry::new(), limiter)
+        } else if options.isolate || !options.limits.is_empty() {
+            let exe = std::env::current_exe().expect("Cannot find current executable!");
+            Backend::Isolated(Supervisor::new(exe))
+        } else {
+            Backend::InProcess(LibraryRegistry::new())
+        };
+
+        Executor {
+            backend,
+            default_timeout_ms: options.timeout_ms,
+            default_limits: options.limits.clone(),
+        }
+    }
+
+    fn expand(&mut self, task: &ExpansionTask) -> ExpansionResult {
+        let mut task = task.clone();
+        task.timeout_ms = task.timeout_ms.or(self.default_timeout_ms);
+        task.limits = task.limits.or(&self.default_limits);
+
+        match &mut self.backend {
+            Backend::InProcess(registry) => {
+                proc_macro_expander::expand_task_with_registry(registry, &task)
+            }
+            Backend::Worker(registry, limiter) => {
+                proc_macro_expander::expand_task_with_limits(registry, limiter, &task)
+            }
+            Backend::Isolated(supervisor) => supervisor.expand(&task),
+        }
+    }
+}
+
+/// Tries to find an id in the task which cannot be parsed, so the error can be matched with it.
+fn task_id(task: &serde_json::Value) -> Option<String> {
+    task.get("id")?.as_str().map(|id| id.to_string())
+}
+
+/// Output for the responses, separated from the stdout of the macros whenever possible.
+fn protocol_output() -> Box<dyn Write> {
+    match proc_macro_expander::isolate_stdout() {
+        Ok(output) => Box::new(output),
+        Err(err) => {
+            eprintln!("Cannot isolate stdout, output of macros may corrupt responses: {}", err);
+            Box::new(std::io::stdout())
+        }
+    }
+}
+
+/// Writes a whole response line at once, so it is never interleaved with other output.
+fn write_response(output: &mut dyn Write, response: &str) {
+    output
+        .write_all(format!("{}\n", response).as_bytes())
+        .and_then(|()| output.flush())
+        .expect("Cannot write response!");
+}
+
+/// Reads a single json array of tasks from stdin and prints a single json array of results.
+fn run_batch(executor: &mut Executor, output: &mut dyn Write) {
     let input = read_stdin();
-    let expansion_tasks: Vec<ExpansionTask> =
+    let expansion_tasks: Vec<serde_json::Value> =
         serde_json::from_str(&input).expect(&format!("Cannot parse '{}'", &input));
 
     let results: Vec<ExpansionResult> = expansion_tasks
-        .iter()
-        .map(|task| proc_macro_expander::expand_task(&task))
+        .into_iter()
+        .map(|task| match serde_json::from_value::<ExpansionTask>(task.clone()) {
+            Ok(task) => executor.expand(&task),
+
+            Err(err) => ExpansionResult::Error {
+                id: task_id(&task),
+                code: ErrorCode::InvalidRequest,
+                reason: format!("Cannot parse task '{}': {}", &task, err),
+                bridge_method: None,
+                diagnostics: vec![],
+            },
+        })
         .collect();
 
+    write_response(
+        output,
+        &serde_json::to_string(&results).expect("Cannot serialize results!"),
+    );
+}
+
+/// Reads one json request per line from stdin and answers each of them with one json line.
+///
+/// Runs until stdin is closed or a shutdown request is received.
+fn run_server(executor: &mut Executor, output: &mut dyn Write) {
+    let stdin = std::io::stdin();
+
+    for line in stdin.lock().lines() {
+        let line = line.expect("Cannot read from stdin!");
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let result = match serde_json::from_str::<ExpansionRequest>(&line) {
+            Ok(ExpansionRequest::Expand(task)) => executor.expand(&task),
+
+            Ok(ExpansionRequest::Shutdown) => break,
+
+            Err(err) => ExpansionResult::Error {
+                id: serde_json::from_str(&line).ok().and_then(|request| task_id(&request)),
+                code: ErrorCode::InvalidRequest,
+                reason: format!("Cannot parse request '{}': {}", &line, err),
+                bridge_method: None,
+                diagnostics: vec![],
+            },
+        };
+
+        write_response(
+            output,
+            &serde_json::to_string(&result).expect("Cannot serialize result!"),
+        );
+    }
+}
+
+/// Prints macros exported by the libraries as a single json array.
+fn run_list(libs: &[PathBuf]) {
+    let macros = proc_macro_expander::list_macros(libs).unwrap_or_else(|err| {
+        eprintln!("Cannot list macros: {}", err);
+        std::process::exit(1);
+    });
+
     println!(
         "{}",
-        &serde_json::to_string(&results).expect("Cannot serialize results!")
+        &serde_json::to_string(&macros).expect("Cannot serialize macros!")
     );
 }
+
+#[derive(Default)]
+struct Options {
+    /// Libraries to list the macros of (`list` subcommand), instead of performing expansions.
+    list: Option<Vec<PathBuf>>,
+    /// Read requests line by line instead of a single batch.
+    server: bool,
+    /// Load libraries and perform expansions in child worker processes.
+    ///
+    /// Implied by any of the resource limits.
+    isolate: bool,
+    /// Run as a worker of an isolated expander.
+    worker: bool,
+    /// Timeout for the tasks which do not specify their own.
+    timeout_ms: Option<u64>,
+    /// Limits for the tasks which do not specify their own.
+    limits: ResourceLimits,
+}
+
+fn parse_value<T: std::str::FromStr>(arg: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for '{}'", arg))?;
+
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for '{}': '{}'", arg, value))
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = std::env::args().skip(1).peekable();
+
+    if args.peek().map(String::as_str) == Some("list") {
+        options.list = Some(args.skip(1).map(PathBuf::from).collect());
+        return Ok(options);
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => options.server = true,
+            "--isolate" => options.isolate = true,
+            "--worker" => options.worker = true,
+            "--timeout-ms" => options.timeout_ms = Some(parse_value(&arg, args.next())?),
+            "--memory-limit-mb" => options.limits.memory_mb = Some(parse_value(&arg, args.next())?),
+            "--cpu-limit-seconds" => {
+                options.limits.cpu_seconds = Some(parse_value(&arg, args.next())?)
+            }
+            "--open-files-limit" => options.limits.open_files = Some(parse_value(&arg, args.next())?),
+            _ => return Err(format!("Unknown argument '{}'", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(2);
+    });
+
+    if let Some(libs) = &options.list {
+        run_list(libs);
+        return;
+    }
+
+    let mut output = protocol_output();
+    let mut executor = Executor::new(&options);
+
+    if options.server {
+        run_server(&mut executor, &mut *output);
+    } else {
+        run_batch(&mut executor, &mut *output);
+    }
+}
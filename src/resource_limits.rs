@@ -0,0 +1,209 @@
+use std::io;
+use std::process::ExitStatus;
+
+use macro_expansion::ResourceLimits;
+use ExpansionError;
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+
+#[cfg(all(unix, not(all(target_os = "linux", target_env = "gnu"))))]
+type Resource = libc::c_int;
+
+/// Applies `ResourceLimits` of the tasks to the current process.
+///
+/// Limits are applied to the whole process, so it is supposed to be used only by isolated
+/// workers. Only soft limits are changed, so every task can set its own limits; limits which
+/// are not set by the task are restored to the values the process has started with.
+pub struct ProcessLimiter {
+    #[cfg(unix)]
+    original: Vec<(Resource, libc::rlimit)>,
+}
+
+#[cfg(unix)]
+fn get_limit(resource: Resource) -> io::Result<libc::rlimit> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(limit)
+}
+
+#[cfg(unix)]
+fn set_soft_limit(resource: Resource, original: &libc::rlimit, value: Option<u64>) -> io::Result<()> {
+    let rlim_cur = match value {
+        Some(value) => std::cmp::min(value as libc::rlim_t, original.rlim_max),
+        None => original.rlim_cur,
+    };
+
+    let limit = libc::rlimit {
+        rlim_cur,
+        rlim_max: original.rlim_max,
+    };
+
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// CPU time used by the current process so far, rounded up to seconds.
+#[cfg(unix)]
+fn used_cpu_seconds() -> io::Result<u64> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let seconds = usage.ru_utime.tv_sec + usage.ru_stime.tv_sec;
+    Ok(seconds as u64 + 1)
+}
+
+impl ProcessLimiter {
+    #[cfg(unix)]
+    pub fn new() -> io::Result<ProcessLimiter> {
+        let mut original = vec![];
+
+        for &resource in &[libc::RLIMIT_AS, libc::RLIMIT_CPU, libc::RLIMIT_NOFILE] {
+            original.push((resource, get_limit(resource)?));
+        }
+
+        Ok(ProcessLimiter { original })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new() -> io::Result<ProcessLimiter> {
+        Ok(ProcessLimiter {})
+    }
+
+    #[cfg(unix)]
+    pub fn apply(&self, limits: &ResourceLimits) -> io::Result<()> {
+        for &(resource, ref original) in &self.original {
+            let value = if resource == libc::RLIMIT_AS {
+                match limits.memory_mb {
+                    Some(mb) => Some(mb.checked_mul(1024 * 1024).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("memory limit of {} MB is out of range", mb),
+                        )
+                    })?),
+                    None => None,
+                }
+            } else if resource == libc::RLIMIT_CPU {
+                // CPU limit is set for the lifetime of the process, so time used by previous
+                // tasks has to be added
+                match limits.cpu_seconds {
+                    Some(seconds) => Some(used_cpu_seconds()? + seconds),
+                    None => None,
+                }
+            } else {
+                limits.open_files
+            };
+
+            set_soft_limit(resource, original, value)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, limits: &ResourceLimits) -> io::Result<()> {
+        if limits.is_empty() {
+            return Ok(());
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "resource limits are supported only on unix",
+        ))
+    }
+}
+
+/// Checks whether a line of the worker's stderr reports a failed allocation.
+///
+/// Rust prints such a line right before aborting the process, when it runs out of memory.
+pub fn is_allocation_failure(line: &str) -> bool {
+    let line = line.trim_end();
+    line.starts_with("memory allocation of ") && line.ends_with(" failed")
+}
+
+/// Finds out whether the worker was killed because of the limits of the task it was running.
+///
+/// Exceeded CPU limit kills process with `SIGXCPU`. Failed allocation makes Rust abort the process,
+/// so `SIGABRT` is treated as an exceeded memory limit when the task has one, but only if the
+/// worker has reported a failed allocation; other aborts are just crashes.
+#[cfg(unix)]
+pub fn exceeded_limit(
+    status: ExitStatus,
+    limits: &ResourceLimits,
+    allocation_failed: bool,
+) -> Option<ExpansionError> {
+    use std::os::unix::process::ExitStatusExt;
+
+    match (status.signal(), limits.cpu_seconds, limits.memory_mb) {
+        (Some(libc::SIGXCPU), Some(limit_seconds), _) => {
+            Some(ExpansionError::CpuLimitExceeded { limit_seconds })
+        }
+
+        (Some(libc::SIGABRT), _, Some(limit_mb)) if allocation_failed => {
+            Some(ExpansionError::MemoryLimitExceeded { limit_mb })
+        }
+
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn exceeded_limit(
+    _status: ExitStatus,
+    _limits: &ResourceLimits,
+    _allocation_failed: bool,
+) -> Option<ExpansionError> {
+    None
+}
+
+/// Reports panics caused by the exhausted file descriptors as an exceeded limit.
+///
+/// Unlike other limits, this one does not kill the process: opening a file just fails with
+/// `EMFILE`, which macros usually unwrap.
+pub fn check_open_files(error: ExpansionError, limits: &ResourceLimits) -> ExpansionError {
+    let limit = match limits.open_files {
+        Some(limit) => limit,
+        None => return error,
+    };
+
+    let message = match &error {
+        ExpansionError::MacroPanic { message, .. } | ExpansionError::ServerPanic { message, .. } => {
+            message.clone()
+        }
+        _ => return error,
+    };
+
+    if is_too_many_open_files(&message) {
+        return ExpansionError::OpenFilesLimitExceeded { limit, message };
+    }
+
+    error
+}
+
+#[cfg(unix)]
+fn is_too_many_open_files(message: &str) -> bool {
+    // Panic message may contain either `Display` or `Debug` form of `io::Error`, but both of them
+    // include the system description of the error
+    let error = io::Error::from_raw_os_error(libc::EMFILE).to_string();
+    let description = error.split(" (os error").next().unwrap_or(&error);
+
+    message.contains(description)
+}
+
+#[cfg(not(unix))]
+fn is_too_many_open_files(_message: &str) -> bool {
+    false
+}
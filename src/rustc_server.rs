@@ -2,18 +2,259 @@ extern crate proc_macro;
 extern crate proc_macro2;
 
 use proc_macro::bridge::{server, TokenTree};
+use std::cell::{Cell, RefCell};
 use std::collections::Bound;
 use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
-use std::iter::FromIterator;
+use std::ops::Range;
+use std::rc::Rc;
 use std::str::FromStr;
 use std::vec::IntoIter;
 
 use proc_macro::{Delimiter, Level, LineColumn, Spacing};
+use source_map::{FileId, SourceMap};
 
-//#[derive(Clone)]
-//pub struct TokenStream;
-type TokenStream = proc_macro2::TokenStream;
+thread_local! {
+    /// Bridge method which has panicked on this thread, if any.
+    static PANICKED_METHOD: Cell<Option<&'static str>> = Cell::new(None);
+
+    /// Whether the panic of the bridge method is caused by invalid arguments of the call.
+    static INVALID_CALL: Cell<bool> = Cell::new(false);
+}
+
+/// Remembers the name of the bridge method it was created in, if that method panics.
+///
+/// Every method of the server enters a guard first, so a panic of the server is always
+/// reported with the method.
+pub struct BridgeMethodGuard(&'static str);
+
+impl BridgeMethodGuard {
+    pub fn enter(method: &'static str) -> BridgeMethodGuard {
+        BridgeMethodGuard(method)
+    }
+}
+
+impl Drop for BridgeMethodGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            PANICKED_METHOD.with(|method| {
+                if method.get().is_none() {
+                    method.set(Some(self.0))
+                }
+            });
+        }
+    }
+}
+
+/// Returns and forgets the name of the bridge method which has panicked on this thread.
+pub fn take_panicked_method() -> Option<&'static str> {
+    PANICKED_METHOD.with(|method| method.take())
+}
+
+/// Rejects the arguments of the current bridge method, which should be guarded by
+/// a `BridgeMethodGuard`. The macro is stopped, and the call is reported as invalid.
+fn invalid_call(message: String) -> ! {
+    INVALID_CALL.with(|invalid| invalid.set(true));
+    panic!("{}", message)
+}
+
+/// Returns and forgets whether a bridge method has rejected its arguments on this thread.
+pub fn take_invalid_call() -> bool {
+    INVALID_CALL.with(|invalid| invalid.replace(false))
+}
+
+const INTEGER_KINDS: &[&str] = &[
+    "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+];
+
+/// How a span has been created.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SpanOrigin {
+    /// Span of an input token.
+    Input,
+    /// `Span::call_site`, also given to the tokens created without a span.
+    CallSite,
+    /// `Span::def_site`.
+    DefSite,
+    /// `Span::mixed_site`.
+    MixedSite,
+}
+
+/// Hygiene of a span: where the identifiers with this span are resolved.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SyntaxContext {
+    /// At the call site of the macro, as if they were written by the user, like input tokens.
+    CallSite,
+    /// At the definition site of the macro.
+    DefSite,
+    /// Like `macro_rules` identifiers: local variables, labels and `$crate` are resolved at the
+    /// definition site, everything else at the call site.
+    MixedSite,
+}
+
+/// Location of a token: byte range in one of the input texts of the expansion.
+///
+/// Spans created by the macro itself cover the whole macro input. Hygiene of the span is
+/// independent of its location, since `Span::resolved_at` may combine any of them.
+///
+/// Two spans are the same only if all of their fields are: the file, the byte range, the origin
+/// and the hygiene context.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MySpanData {
+    pub file: FileId,
+    pub lo: u32,
+    pub hi: u32,
+    pub origin: SpanOrigin,
+    pub ctx: SyntaxContext,
+}
+
+impl MySpanData {
+    fn join(self, other: MySpanData) -> Option<MySpanData> {
+        if self.file != other.file {
+            return None;
+        }
+
+        let origin = if self.origin == other.origin {
+            self.origin
+        } else {
+            SpanOrigin::CallSite
+        };
+
+        Some(MySpanData {
+            file: self.file,
+            lo: std::cmp::min(self.lo, other.lo),
+            hi: std::cmp::max(self.hi, other.hi),
+            origin,
+            ctx: self.ctx,
+        })
+    }
+
+    /// Text of the span in `source_map`, if the span is within the text of its file.
+    fn text(self, source_map: &SourceMap) -> Option<&str> {
+        source_map.text(self.file).get(self.lo as usize..self.hi as usize)
+    }
+
+    /// Span of the first char of the span, e.g. of the opening delimiter of a group.
+    fn first_char(self, source_map: &SourceMap) -> MySpanData {
+        let len = self.text(source_map).and_then(|text| text.chars().next()).map_or(0, char::len_utf8);
+
+        MySpanData { hi: self.lo + len as u32, ..self }
+    }
+
+    /// Span of the last char of the span, e.g. of the closing delimiter of a group.
+    fn last_char(self, source_map: &SourceMap) -> MySpanData {
+        let len = self.text(source_map).and_then(|text| text.chars().next_back()).map_or(0, char::len_utf8);
+
+        MySpanData { lo: self.hi - len as u32, ..self }
+    }
+}
+
+type MyTokenTree = TokenTree<Group, MyPunctData, MyIdentData, Literal>;
+
+#[derive(Clone, Default)]
+pub struct TokenStream {
+    trees: Vec<MyTokenTree>,
+}
+
+impl TokenStream {
+    pub fn new() -> TokenStream {
+        TokenStream::default()
+    }
+}
+
+impl TokenStream {
+    /// Formats the stream like proc_macro2 does, reporting the byte range of each token
+    /// in `out` together with its span.
+    ///
+    /// Delimiters of groups are reported separately, with the first and the last char of the
+    /// group span in `source_map`.
+    pub fn print<F: FnMut(Range<usize>, MySpanData)>(
+        &self,
+        source_map: &SourceMap,
+        out: &mut String,
+        on_token: &mut F,
+    ) {
+        let mut joint = false;
+        for (i, tree) in self.trees.iter().enumerate() {
+            if i != 0 && !joint {
+                out.push(' ');
+            }
+            joint = false;
+
+            let start = out.len();
+            let span = match tree {
+                TokenTree::Group(group) => {
+                    group.print(source_map, out, on_token);
+                    continue;
+                }
+                TokenTree::Ident(ident) => {
+                    if ident.is_raw {
+                        out.push_str("r#");
+                    }
+                    out.push_str(&ident.name);
+                    ident.span
+                }
+                TokenTree::Punct(punct) => {
+                    joint = punct.joint;
+                    out.push(punct.ch);
+                    punct.span
+                }
+                TokenTree::Literal(literal) => {
+                    out.push_str(&literal.text);
+                    literal.span
+                }
+            };
+
+            on_token(start..out.len(), span);
+        }
+    }
+}
+
+impl TokenStream {
+    /// Top-level `compile_error!("...")` invocations of the stream, e.g. ones produced by
+    /// `syn::Error::to_compile_error`, as error diagnostics.
+    ///
+    /// The span of a diagnostic covers the invocation from `compile_error` to its arguments.
+    pub fn compile_errors(&self) -> Vec<MyDiagnostic> {
+        let mut errors = vec![];
+
+        for window in self.trees.windows(3) {
+            let (ident, group) = match window {
+                [TokenTree::Ident(ident), TokenTree::Punct(bang), TokenTree::Group(group)]
+                    if ident.name == "compile_error" && bang.ch == '!' =>
+                {
+                    (ident, group)
+                }
+                _ => continue,
+            };
+
+            let message = match group.stream.trees.as_slice() {
+                [TokenTree::Literal(literal)] => match syn::parse_str::<syn::LitStr>(&literal.text) {
+                    Ok(lit) => lit.value(),
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+
+            errors.push(MyDiagnostic {
+                level: Level::Error,
+                message,
+                spans: vec![ident.span.join(group.span).unwrap_or(ident.span)],
+                children: vec![],
+            });
+        }
+
+        errors
+    }
+}
+
+impl TokenStream {
+    /// Formats the stream like proc_macro2 does.
+    pub fn to_string(&self, source_map: &SourceMap) -> String {
+        let mut out = String::new();
+        self.print(source_map, &mut out, &mut |_, _| {});
+        out
+    }
+}
 
 pub struct TokenStreamBuilder {
     acc: TokenStream,
@@ -27,7 +268,7 @@ impl TokenStreamBuilder {
     }
 
     fn push(&mut self, stream: TokenStream) {
-        self.acc.extend(stream.into_iter())
+        self.acc.trees.extend(stream.trees)
     }
 
     fn build(self) -> TokenStream {
@@ -37,98 +278,132 @@ impl TokenStreamBuilder {
 
 #[derive(Clone)]
 pub struct TokenStreamIter {
-    trees: IntoIter<proc_macro2::TokenTree>,
+    trees: IntoIter<MyTokenTree>,
 }
 
-//#[derive(Clone)]
-//pub struct Group;
-type Group = proc_macro2::Group;
+#[derive(Clone)]
+pub struct Group {
+    delimiter: Delimiter,
+    stream: TokenStream,
+    span: MySpanData,
+}
 
-#[derive(Hash, Eq, PartialEq, Copy, Clone)]
-pub struct MyPunct(u32);
+impl Group {
+    fn print<F: FnMut(Range<usize>, MySpanData)>(
+        &self,
+        source_map: &SourceMap,
+        out: &mut String,
+        on_token: &mut F,
+    ) {
+        let (open, close) = match self.delimiter {
+            Delimiter::Parenthesis => ("(", ")"),
+            Delimiter::Brace => ("{", "}"),
+            Delimiter::Bracket => ("[", "]"),
+            Delimiter::None => ("", ""),
+        };
+
+        // Delimiters are separated from the stream by spaces, like proc_macro2 prints them
+        let start = out.len();
+        out.push_str(open);
+        if !open.is_empty() {
+            on_token(start..out.len(), self.span.first_char(source_map));
+        }
 
-#[derive(Clone)]
-struct MyPunctData(proc_macro2::Punct);
+        out.push(' ');
+        if !self.stream.trees.is_empty() {
+            self.stream.print(source_map, out, on_token);
+            out.push(' ');
+        }
 
-impl Hash for MyPunctData {
-    fn hash<H: Hasher>(&self, hasher: &mut H) {
-        hash_span(&self.0.span(), hasher);
-        self.0.as_char().hash(hasher);
+        let start = out.len();
+        out.push_str(close);
+        if !close.is_empty() {
+            on_token(start..out.len(), self.span.last_char(source_map));
+        }
     }
 }
 
-impl Eq for MyPunctData {}
+#[derive(Hash, Eq, PartialEq, Copy, Clone)]
+pub struct MyPunct(u32);
 
-impl PartialEq for MyPunctData {
-    fn eq(&self, other: &Self) -> bool {
-        let punct = &self.0;
-        let other = &other.0;
-        return punct.span().eq(&other.span())
-            && punct.as_char() == other.as_char()
-            && punct.spacing() == other.spacing();
-    }
+#[derive(Clone)]
+pub struct MyPunctData {
+    ch: char,
+    joint: bool,
+    span: MySpanData,
 }
 
 #[derive(Hash, Eq, PartialEq, Copy, Clone)]
 pub struct MyIdent(u32);
 
 #[derive(Clone)]
-struct MyIdentData(proc_macro2::Ident);
-
-fn hash_span<H: Hasher>(span: &proc_macro2::Span, hasher: &mut H) {
-    let start = span.start();
-    start.line.hash(hasher);
-    start.column.hash(hasher);
-
-    let end = span.end();
-    end.line.hash(hasher);
-    end.column.hash(hasher);
+pub struct MyIdentData {
+    /// Name without the `r#` prefix of raw identifiers.
+    name: String,
+    is_raw: bool,
+    span: MySpanData,
 }
 
-impl Hash for MyIdentData {
-    fn hash<H: Hasher>(&self, hasher: &mut H) {
-        self.0.hash(hasher)
-    }
-}
+impl MyIdentData {
+    /// Checks the identifier like rustc does in `Ident::new`: the name should be lexed as
+    /// a single identifier, and path segment keywords cannot be raw.
+    fn is_valid(name: &str, is_raw: bool) -> bool {
+        if name.contains('#') || is_raw && ["_", "crate", "self", "Self", "super"].contains(&name) {
+            return false;
+        }
 
-impl Eq for MyIdentData {}
+        let text = if is_raw { format!("r#{}", name) } else { name.to_string() };
+        let stream = match proc_macro2::TokenStream::from_str(&text) {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
 
-impl PartialEq for MyIdentData {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.eq(&other.0)
+        let mut trees = stream.into_iter();
+        match (trees.next(), trees.next()) {
+            (Some(proc_macro2::TokenTree::Ident(ident)), None) => ident == text,
+            _ => false,
+        }
     }
 }
 
-//#[derive(Clone)]
-//pub struct Literal;
-type Literal = proc_macro2::Literal;
+#[derive(Clone, Debug)]
+pub struct Literal {
+    /// Literal exactly as it is written in the source, e.g. `"a\n"` or `1u8`.
+    text: String,
+    span: MySpanData,
+}
 
-//#[derive(Clone)]
-//pub struct SourceFile;
-type SourceFile = proc_macro2::SourceFile;
+#[derive(Clone, PartialEq, Eq)]
+pub struct SourceFile {
+    file: FileId,
+}
 
 #[derive(Hash, Eq, PartialEq, Copy, Clone)]
 pub struct MySpan(u32);
 
-#[derive(Copy, Clone)]
-struct MySpanData(proc_macro2::Span);
-
-impl Hash for MySpanData {
-    fn hash<H: Hasher>(&self, hasher: &mut H) {
-        let column = self.0.start();
-        column.line.hash(hasher);
-        column.column.hash(hasher);
-    }
+/// Spans which a diagnostic points to, resolved from the span interner when pushed.
+#[derive(Clone, Debug, Default)]
+pub struct MultiSpan {
+    spans: Vec<MySpanData>,
 }
 
-impl Eq for MySpanData {}
-
-impl PartialEq for MySpanData {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.eq(&other.0)
-    }
+/// Diagnostic reported by the macro, with notes and help messages attached to it as children.
+#[derive(Clone, Debug)]
+pub struct MyDiagnostic {
+    pub level: Level,
+    pub message: String,
+    pub spans: Vec<MySpanData>,
+    pub children: Vec<MyDiagnostic>,
 }
 
+/// Diagnostics emitted during the expansion, shared with the caller, since the server itself
+/// is consumed by the macro client.
+pub type EmittedDiagnostics = Rc<RefCell<Vec<MyDiagnostic>>>;
+
+/// Spans given to the macro during a single expansion.
+///
+/// Spans are plain values, identified by all of their fields, so equal spans share a handle and
+/// distinct ones never do. This makes `Span` equality on the macro side the equality of spans.
 #[derive(Default)]
 struct MySpanInterner {
     spans: HashMap<MySpanData, u32>,
@@ -153,73 +428,187 @@ impl MySpanInterner {
     }
 }
 
-#[derive(Default)]
-struct MyIdentInterner {
-    idents: HashMap<MyIdentData, u32>,
-    ident_data: Vec<MyIdentData>,
+/// Identifiers or puncts given to the macro during a single expansion.
+///
+/// Unlike spans, they are not deduplicated: each created token (including the ones created by
+/// `with_span`) gets its own handle, so changing one token never affects another. All of them
+/// are freed together with the server, when the expansion finishes.
+struct HandleStore<T> {
+    data: Vec<T>,
 }
 
-impl MyIdentInterner {
-    fn intern(&mut self, data: &MyIdentData) -> u32 {
-        if let Some(index) = self.idents.get(data) {
-            return *index;
-        }
-
-        let index = self.idents.len() as u32;
-        self.ident_data.push(data.clone());
-        self.idents.insert(data.clone(), index);
-
-        index
+impl<T> Default for HandleStore<T> {
+    fn default() -> HandleStore<T> {
+        HandleStore { data: vec![] }
     }
+}
 
-    fn get(&self, index: u32) -> &MyIdentData {
-        &self.ident_data[index as usize]
+impl<T> HandleStore<T> {
+    fn alloc(&mut self, data: T) -> u32 {
+        self.data.push(data);
+        self.data.len() as u32 - 1
     }
 
-    fn get_mut(&mut self, index: u32) -> &mut MyIdentData {
-        self.ident_data
-            .get_mut(index as usize)
-            .expect("Should be consistent")
+    fn get(&self, handle: u32) -> &T {
+        &self.data[handle as usize]
     }
 }
 
-#[derive(Default)]
-struct MyPunctInterner {
-    puncts: HashMap<MyPunctData, u32>,
-    punct_data: Vec<MyPunctData>,
+pub struct Rustc {
+    source_map: Rc<SourceMap>,
+    /// Span of the whole macro input.
+    call_site: MySpanData,
+    span_interner: MySpanInterner,
+    idents: HandleStore<MyIdentData>,
+    puncts: HandleStore<MyPunctData>,
+    diagnostics: EmittedDiagnostics,
+    //    def_side: MySpan,
+    //    call_site: MySpan,
 }
 
-impl MyPunctInterner {
-    fn intern(&mut self, data: &MyPunctData) -> u32 {
-        if let Some(index) = self.puncts.get(data) {
-            return *index;
+impl Rustc {
+    /// Creates a server for the expansion of the macro, which is invoked on the text of
+    /// `input_file`.
+    pub fn new(source_map: Rc<SourceMap>, input_file: FileId) -> Rustc {
+        let call_site = MySpanData {
+            file: input_file,
+            lo: 0,
+            hi: source_map.text(input_file).len() as u32,
+            origin: SpanOrigin::CallSite,
+            ctx: SyntaxContext::CallSite,
+        };
+
+        Rustc {
+            source_map,
+            call_site,
+            span_interner: MySpanInterner::default(),
+            idents: HandleStore::default(),
+            puncts: HandleStore::default(),
+            diagnostics: EmittedDiagnostics::default(),
         }
+    }
+
+    /// Diagnostics emitted by the macro; they are available even if the macro panics.
+    pub fn diagnostics(&self) -> EmittedDiagnostics {
+        self.diagnostics.clone()
+    }
 
-        let index = self.puncts.len() as u32;
-        self.punct_data.push(data.clone());
-        self.puncts.insert(data.clone(), index);
+    /// Span of the macro invocation in the user's code, which all spans created by the macro
+    /// are expanded from.
+    fn invocation(&self) -> MySpanData {
+        MySpanData {
+            origin: SpanOrigin::Input,
+            ..self.call_site
+        }
+    }
 
-        index
+    /// Converts tokens parsed from the text of `file`, keeping their positions in it.
+    ///
+    /// Tokens which were not parsed from the text (e.g. created during preprocessing) are
+    /// given the call site span.
+    pub fn token_stream(&self, stream: proc_macro2::TokenStream, file: FileId) -> TokenStream {
+        self.convert_stream(stream, Some(file))
+    }
+
+    /// `file` is `None` if tokens are not parsed from any of the input texts.
+    fn convert_stream(&self, stream: proc_macro2::TokenStream, file: Option<FileId>) -> TokenStream {
+        let trees = stream
+            .into_iter()
+            .map(|tree| match tree {
+                proc_macro2::TokenTree::Group(group) => TokenTree::Group(Group {
+                    delimiter: delim_to_external(group.delimiter()),
+                    span: self.convert_span(group.span(), file),
+                    stream: self.convert_stream(group.stream(), file),
+                }),
+
+                proc_macro2::TokenTree::Ident(ident) => {
+                    let name = ident.to_string();
+                    let is_raw = name.starts_with("r#");
+
+                    TokenTree::Ident(MyIdentData {
+                        name: if is_raw { name[2..].to_string() } else { name },
+                        is_raw,
+                        span: self.convert_span(ident.span(), file),
+                    })
+                }
+
+                proc_macro2::TokenTree::Punct(punct) => TokenTree::Punct(MyPunctData {
+                    ch: punct.as_char(),
+                    joint: punct.spacing() == proc_macro2::Spacing::Joint,
+                    span: self.convert_span(punct.span(), file),
+                }),
+
+                proc_macro2::TokenTree::Literal(literal) => TokenTree::Literal(Literal {
+                    text: literal.to_string(),
+                    span: self.convert_span(literal.span(), file),
+                }),
+            })
+            .collect();
+
+        TokenStream { trees }
+    }
+
+    fn convert_span(&self, span: proc_macro2::Span, file: Option<FileId>) -> MySpanData {
+        let file = match file {
+            Some(file) => file,
+            None => return self.call_site,
+        };
+
+        let (start, end) = (span.start(), span.end());
+
+        // Call site spans of proc_macro2 are empty, unlike spans of any parsed token
+        if (start.line, start.column) == (end.line, end.column) {
+            return self.call_site;
+        }
+
+        MySpanData {
+            file,
+            lo: self.source_map.position(file, start.line, start.column),
+            hi: self.source_map.position(file, end.line, end.column),
+            origin: SpanOrigin::Input,
+            ctx: SyntaxContext::CallSite,
+        }
     }
 
-    fn get(&self, index: u32) -> &MyPunctData {
-        &self.punct_data[index as usize]
+    fn intern_span(&mut self, span: MySpanData) -> MySpan {
+        MySpan(self.span_interner.intern(&span))
     }
 
-    fn get_mut(&mut self, index: u32) -> &mut MyPunctData {
-        self.punct_data
-            .get_mut(index as usize)
-            .expect("Should be consistent")
+    /// Creates a numeric literal from its exact text, like rustc does, so its spelling
+    /// (`0xFF`, `1_000`, `1e10`) is preserved.
+    ///
+    /// Text which is not a single numeric literal with the `suffix` is rejected, so the macro
+    /// cannot produce tokens which would be lexed differently.
+    fn numeric_literal(&self, text: &str, suffix: &str, float: bool) -> Literal {
+        let text = format!("{}{}", text, suffix);
+        let unsigned = if text.starts_with('-') { &text[1..] } else { &text[..] };
+
+        let valid = !unsigned.starts_with('-')
+            && match syn::parse_str::<syn::Lit>(unsigned) {
+                Ok(syn::Lit::Int(lit)) => lit.suffix() == suffix && lit.to_string() == unsigned,
+                Ok(syn::Lit::Float(lit)) => {
+                    float && lit.suffix() == suffix && lit.to_string() == unsigned
+                }
+                _ => false,
+            };
+
+        if !valid {
+            let kind = if float { "float" } else { "integer" };
+            invalid_call(format!("'{}' is not a valid {} literal", text, kind));
+        }
+
+        Literal {
+            text,
+            span: self.call_site,
+        }
     }
-}
 
-#[derive(Default)]
-pub struct Rustc {
-    span_interner: MySpanInterner,
-    ident_interner: MyIdentInterner,
-    punct_interner: MyPunctInterner,
-    //    def_side: MySpan,
-    //    call_site: MySpan,
+    fn literal(&self, literal: proc_macro2::Literal) -> Literal {
+        Literal {
+            text: literal.to_string(),
+            span: self.call_site,
+        }
+    }
 }
 
 impl server::Types for Rustc {
@@ -231,70 +620,77 @@ impl server::Types for Rustc {
     type Ident = MyIdent;
     type Literal = Literal;
     type SourceFile = SourceFile;
-    type Diagnostic = proc_macro::Diagnostic;
+    type Diagnostic = MyDiagnostic;
     type Span = MySpan;
-    type MultiSpan = Vec<proc_macro::Span>;
+    type MultiSpan = MultiSpan;
 }
 
 impl server::TokenStream for Rustc {
     fn new(&mut self) -> Self::TokenStream {
+        let _guard = BridgeMethodGuard::enter("TokenStream::new");
         Self::TokenStream::new()
     }
 
     fn is_empty(&mut self, stream: &Self::TokenStream) -> bool {
-        stream.is_empty()
+        let _guard = BridgeMethodGuard::enter("TokenStream::is_empty");
+        stream.trees.is_empty()
     }
+    /// Bridge cannot return a `LexError` to the macro (`FromStr` of the client never fails),
+    /// so text which cannot be lexed stops the macro with an invalid call.
     fn from_str(&mut self, src: &str) -> Self::TokenStream {
-        Self::TokenStream::from_str(src).expect("cannot parse string")
+        let _guard = BridgeMethodGuard::enter("TokenStream::from_str");
+        let stream = match proc_macro2::TokenStream::from_str(src) {
+            Ok(stream) => stream,
+            Err(err) => invalid_call(format!("cannot parse '{}': {:?}", src, err)),
+        };
+
+        self.convert_stream(stream, None)
     }
     fn to_string(&mut self, stream: &Self::TokenStream) -> String {
-        stream.to_string()
+        let _guard = BridgeMethodGuard::enter("TokenStream::to_string");
+        stream.to_string(&self.source_map)
     }
     fn from_token_tree(
         &mut self,
         tree: TokenTree<Self::Group, Self::Punct, Self::Ident, Self::Literal>,
     ) -> Self::TokenStream {
-        match tree {
-            TokenTree::Group(group) => {
-                let tree = proc_macro2::TokenTree::from(group);
-                Self::TokenStream::from_iter(vec![tree])
-            }
+        let _guard = BridgeMethodGuard::enter("TokenStream::from_token_tree");
+        let tree = match tree {
+            TokenTree::Group(group) => TokenTree::Group(group),
 
             TokenTree::Ident(MyIdent(index)) => {
-                let MyIdentData(ident) = self.ident_interner.get(index).clone();
-                let tree = proc_macro2::TokenTree::from(ident);
-                Self::TokenStream::from_iter(vec![tree])
+                TokenTree::Ident(self.idents.get(index).clone())
             }
 
-            TokenTree::Literal(group) => {
-                let tree = proc_macro2::TokenTree::from(group);
-                Self::TokenStream::from_iter(vec![tree])
-            }
+            TokenTree::Literal(literal) => TokenTree::Literal(literal),
 
             TokenTree::Punct(MyPunct(index)) => {
-                let MyPunctData(punct) = self.punct_interner.get(index).clone();
-                let tree = proc_macro2::TokenTree::from(punct);
-                Self::TokenStream::from_iter(vec![tree])
+                TokenTree::Punct(self.puncts.get(index).clone())
             }
-        }
+        };
+
+        Self::TokenStream { trees: vec![tree] }
     }
 
     fn into_iter(&mut self, stream: Self::TokenStream) -> Self::TokenStreamIter {
-        let trees: Vec<proc_macro2::TokenTree> = stream.into_iter().collect();
+        let _guard = BridgeMethodGuard::enter("TokenStream::into_iter");
         TokenStreamIter {
-            trees: trees.into_iter(),
+            trees: stream.trees.into_iter(),
         }
     }
 }
 
 impl server::TokenStreamBuilder for Rustc {
     fn new(&mut self) -> Self::TokenStreamBuilder {
+        let _guard = BridgeMethodGuard::enter("TokenStreamBuilder::new");
         Self::TokenStreamBuilder::new()
     }
     fn push(&mut self, builder: &mut Self::TokenStreamBuilder, stream: Self::TokenStream) {
+        let _guard = BridgeMethodGuard::enter("TokenStreamBuilder::push");
         builder.push(stream)
     }
     fn build(&mut self, builder: Self::TokenStreamBuilder) -> Self::TokenStream {
+        let _guard = BridgeMethodGuard::enter("TokenStreamBuilder::build");
         builder.build()
     }
 }
@@ -304,31 +700,19 @@ impl server::TokenStreamIter for Rustc {
         &mut self,
         iter: &mut Self::TokenStreamIter,
     ) -> Option<TokenTree<Self::Group, Self::Punct, Self::Ident, Self::Literal>> {
+        let _guard = BridgeMethodGuard::enter("TokenStreamIter::next");
         iter.trees.next().map(|tree| match tree {
-            proc_macro2::TokenTree::Group(group) => TokenTree::Group(group),
+            TokenTree::Group(group) => TokenTree::Group(group),
 
-            proc_macro2::TokenTree::Ident(ident) => {
-                TokenTree::Ident(MyIdent(self.ident_interner.intern(&MyIdentData(ident))))
-            }
+            TokenTree::Ident(ident) => TokenTree::Ident(MyIdent(self.idents.alloc(ident))),
 
-            proc_macro2::TokenTree::Literal(literal) => TokenTree::Literal(literal),
+            TokenTree::Literal(literal) => TokenTree::Literal(literal),
 
-            proc_macro2::TokenTree::Punct(punct) => {
-                TokenTree::Punct(MyPunct(self.punct_interner.intern(&MyPunctData(punct))))
-            }
+            TokenTree::Punct(punct) => TokenTree::Punct(MyPunct(self.puncts.alloc(punct))),
         })
     }
 }
 
-fn delim_to_internal(d: proc_macro::Delimiter) -> proc_macro2::Delimiter {
-    match d {
-        proc_macro::Delimiter::Parenthesis => proc_macro2::Delimiter::Parenthesis,
-        proc_macro::Delimiter::Brace => proc_macro2::Delimiter::Brace,
-        proc_macro::Delimiter::Bracket => proc_macro2::Delimiter::Bracket,
-        proc_macro::Delimiter::None => proc_macro2::Delimiter::None,
-    }
-}
-
 fn delim_to_external(d: proc_macro2::Delimiter) -> proc_macro::Delimiter {
     match d {
         proc_macro2::Delimiter::Parenthesis => proc_macro::Delimiter::Parenthesis,
@@ -338,246 +722,245 @@ fn delim_to_external(d: proc_macro2::Delimiter) -> proc_macro::Delimiter {
     }
 }
 
-fn spacing_to_internal(spacing: proc_macro::Spacing) -> proc_macro2::Spacing {
-    match spacing {
-        proc_macro::Spacing::Alone => proc_macro2::Spacing::Alone,
-        proc_macro::Spacing::Joint => proc_macro2::Spacing::Joint,
-    }
-}
-
-fn spacing_to_external(spacing: proc_macro2::Spacing) -> proc_macro::Spacing {
-    match spacing {
-        proc_macro2::Spacing::Alone => proc_macro::Spacing::Alone,
-        proc_macro2::Spacing::Joint => proc_macro::Spacing::Joint,
-    }
-}
-
 impl server::Group for Rustc {
     fn new(&mut self, delimiter: Delimiter, stream: Self::TokenStream) -> Self::Group {
-        Self::Group::new(delim_to_internal(delimiter), stream)
+        let _guard = BridgeMethodGuard::enter("Group::new");
+        Group {
+            delimiter,
+            stream,
+            span: self.call_site,
+        }
     }
     fn delimiter(&mut self, group: &Self::Group) -> Delimiter {
-        delim_to_external(group.delimiter())
+        let _guard = BridgeMethodGuard::enter("Group::delimiter");
+        group.delimiter
     }
     fn stream(&mut self, group: &Self::Group) -> Self::TokenStream {
-        group.stream()
+        let _guard = BridgeMethodGuard::enter("Group::stream");
+        group.stream.clone()
     }
     fn span(&mut self, group: &Self::Group) -> Self::Span {
-        MySpan(self.span_interner.intern(&MySpanData(group.span())))
+        let _guard = BridgeMethodGuard::enter("Group::span");
+        self.intern_span(group.span)
     }
 
     fn set_span(&mut self, group: &mut Self::Group, span: Self::Span) {
-        let MySpanData(span) = *self.span_interner.get(span.0);
-        group.set_span(span);
+        let _guard = BridgeMethodGuard::enter("Group::set_span");
+        group.span = *self.span_interner.get(span.0);
     }
 
     fn span_open(&mut self, group: &Self::Group) -> Self::Span {
-        MySpan(self.span_interner.intern(&MySpanData(group.span_open())))
+        let _guard = BridgeMethodGuard::enter("Group::span_open");
+        let span = group.span.first_char(&self.source_map);
+        self.intern_span(span)
     }
 
     fn span_close(&mut self, group: &Self::Group) -> Self::Span {
-        MySpan(self.span_interner.intern(&MySpanData(group.span_close())))
+        let _guard = BridgeMethodGuard::enter("Group::span_close");
+        let span = group.span.last_char(&self.source_map);
+        self.intern_span(span)
     }
 }
 
 impl server::Punct for Rustc {
     fn new(&mut self, ch: char, spacing: Spacing) -> Self::Punct {
-        MyPunct(
-            self.punct_interner
-                .intern(&MyPunctData(proc_macro2::Punct::new(
-                    ch,
-                    spacing_to_internal(spacing),
-                ))),
-        )
+        let _guard = BridgeMethodGuard::enter("Punct::new");
+        MyPunct(self.puncts.alloc(MyPunctData {
+            ch,
+            joint: spacing == Spacing::Joint,
+            span: self.call_site,
+        }))
     }
 
     fn as_char(&mut self, punct: Self::Punct) -> char {
-        let MyPunctData(punct) = self.punct_interner.get(punct.0);
-        punct.as_char()
+        let _guard = BridgeMethodGuard::enter("Punct::as_char");
+        self.puncts.get(punct.0).ch
     }
     fn spacing(&mut self, punct: Self::Punct) -> Spacing {
-        let MyPunctData(punct) = self.punct_interner.get(punct.0);
-        spacing_to_external(punct.spacing())
+        let _guard = BridgeMethodGuard::enter("Punct::spacing");
+        if self.puncts.get(punct.0).joint {
+            Spacing::Joint
+        } else {
+            Spacing::Alone
+        }
     }
     fn span(&mut self, punct: Self::Punct) -> Self::Span {
-        let MyPunctData(punct) = self.punct_interner.get(punct.0);
-        MySpan(self.span_interner.intern(&MySpanData(punct.span())))
+        let _guard = BridgeMethodGuard::enter("Punct::span");
+        let span = self.puncts.get(punct.0).span;
+        self.intern_span(span)
     }
     fn with_span(&mut self, punct: Self::Punct, span: Self::Span) -> Self::Punct {
-        let MySpanData(span) = *self.span_interner.get(span.0);
-        self.punct_interner.get_mut(punct.0).0.set_span(span);
-        punct
+        let _guard = BridgeMethodGuard::enter("Punct::with_span");
+        let span = *self.span_interner.get(span.0);
+        let punct = MyPunctData {
+            span,
+            ..self.puncts.get(punct.0).clone()
+        };
+
+        MyPunct(self.puncts.alloc(punct))
     }
 }
 
 impl server::Ident for Rustc {
-    fn new(&mut self, string: &str, span: Self::Span, _is_raw: bool) -> Self::Ident {
-        let MySpanData(span) = self.span_interner.get(span.0);
-        MyIdent(
-            self.ident_interner
-                .intern(&MyIdentData(proc_macro2::Ident::new(string, *span))),
-        )
+    fn new(&mut self, string: &str, span: Self::Span, is_raw: bool) -> Self::Ident {
+        let _guard = BridgeMethodGuard::enter("Ident::new");
+        if !MyIdentData::is_valid(string, is_raw) {
+            let kind = if is_raw { "raw identifier" } else { "identifier" };
+            invalid_call(format!("'{}' is not a valid {}", string, kind));
+        }
+
+        let span = *self.span_interner.get(span.0);
+        MyIdent(self.idents.alloc(MyIdentData {
+            name: string.to_string(),
+            is_raw,
+            span,
+        }))
     }
 
     fn span(&mut self, ident: Self::Ident) -> Self::Span {
-        let MyIdentData(ident) = self.ident_interner.get(ident.0);
-        MySpan(self.span_interner.intern(&MySpanData(ident.span())))
+        let _guard = BridgeMethodGuard::enter("Ident::span");
+        let span = self.idents.get(ident.0).span;
+        self.intern_span(span)
     }
     fn with_span(&mut self, ident: Self::Ident, span: Self::Span) -> Self::Ident {
-        let MySpanData(span) = *self.span_interner.get(span.0);
-        self.ident_interner.get_mut(ident.0).0.set_span(span);
-        ident
+        let _guard = BridgeMethodGuard::enter("Ident::with_span");
+        let span = *self.span_interner.get(span.0);
+        let ident = MyIdentData {
+            span,
+            ..self.idents.get(ident.0).clone()
+        };
+
+        MyIdent(self.idents.alloc(ident))
     }
 }
 
 impl server::Literal for Rustc {
     // FIXME(eddyb) `Literal` should not expose internal `Debug` impls.
     fn debug(&mut self, literal: &Self::Literal) -> String {
+        let _guard = BridgeMethodGuard::enter("Literal::debug");
         format!("{:?}", literal)
     }
 
     fn integer(&mut self, n: &str) -> Self::Literal {
-        // note: this implementation may be incorrect
-        let n: i128 = n.parse().unwrap();
-        Literal::i128_unsuffixed(n)
+        let _guard = BridgeMethodGuard::enter("Literal::integer");
+        self.numeric_literal(n, "", false)
     }
 
     fn typed_integer(&mut self, n: &str, kind: &str) -> Self::Literal {
-        match kind {
-            "u8" => {
-                let n: u8 = n.parse().unwrap();
-                Literal::u8_suffixed(n)
-            }
-
-            "u16" => {
-                let n: u16 = n.parse().unwrap();
-                Literal::u16_suffixed(n)
-            }
-
-            "u32" => {
-                let n: u32 = n.parse().unwrap();
-                Literal::u32_suffixed(n)
-            }
-
-            "u64" => {
-                let n: u64 = n.parse().unwrap();
-                Literal::u64_suffixed(n)
-            }
-
-            "u128" => {
-                let n: u128 = n.parse().unwrap();
-                Literal::u128_suffixed(n)
-            }
-
-            "usize" => {
-                let n: usize = n.parse().unwrap();
-                Literal::usize_suffixed(n)
-            }
-
-            "i8" => {
-                let n: i8 = n.parse().unwrap();
-                Literal::i8_suffixed(n)
-            }
-
-            "i16" => {
-                let n: i16 = n.parse().unwrap();
-                Literal::i16_suffixed(n)
-            }
-
-            "i32" => {
-                let n: i32 = n.parse().unwrap();
-                Literal::i32_suffixed(n)
-            }
-
-            "i64" => {
-                let n: i64 = n.parse().unwrap();
-                Literal::i64_suffixed(n)
-            }
-
-            "i128" => {
-                let n: i128 = n.parse().unwrap();
-                Literal::i128_suffixed(n)
-            }
-
-            _ => unimplemented!("unknown args for typed_integer: n {}, kind {}", n, kind),
+        let _guard = BridgeMethodGuard::enter("Literal::typed_integer");
+        if !INTEGER_KINDS.contains(&kind) {
+            invalid_call(format!("unknown integer kind '{}'", kind));
         }
+
+        self.numeric_literal(n, kind, false)
     }
 
     fn float(&mut self, n: &str) -> Self::Literal {
-        // note: this implementation may be incorrect
-        let n: f64 = n.parse().unwrap();
-        Literal::f64_unsuffixed(n)
+        let _guard = BridgeMethodGuard::enter("Literal::float");
+        self.numeric_literal(n, "", true)
     }
 
     fn f32(&mut self, n: &str) -> Self::Literal {
-        let n: f32 = n.parse().unwrap();
-        Literal::f32_suffixed(n)
+        let _guard = BridgeMethodGuard::enter("Literal::f32");
+        self.numeric_literal(n, "f32", true)
     }
 
     fn f64(&mut self, n: &str) -> Self::Literal {
-        let n: f64 = n.parse().unwrap();
-        Literal::f64_suffixed(n)
+        let _guard = BridgeMethodGuard::enter("Literal::f64");
+        self.numeric_literal(n, "f64", true)
     }
 
     fn string(&mut self, string: &str) -> Self::Literal {
-        Self::Literal::string(string)
+        let _guard = BridgeMethodGuard::enter("Literal::string");
+        self.literal(proc_macro2::Literal::string(string))
     }
 
     fn character(&mut self, ch: char) -> Self::Literal {
-        Self::Literal::character(ch)
+        let _guard = BridgeMethodGuard::enter("Literal::character");
+        self.literal(proc_macro2::Literal::character(ch))
     }
 
     fn byte_string(&mut self, bytes: &[u8]) -> Self::Literal {
-        Self::Literal::byte_string(bytes)
+        let _guard = BridgeMethodGuard::enter("Literal::byte_string");
+        self.literal(proc_macro2::Literal::byte_string(bytes))
     }
 
     fn span(&mut self, literal: &Self::Literal) -> Self::Span {
-        MySpan(self.span_interner.intern(&MySpanData(literal.span())))
+        let _guard = BridgeMethodGuard::enter("Literal::span");
+        self.intern_span(literal.span)
     }
 
     fn set_span(&mut self, literal: &mut Self::Literal, span: Self::Span) {
-        let MySpanData(span) = *self.span_interner.get(span.0);
-        literal.set_span(span);
+        let _guard = BridgeMethodGuard::enter("Literal::set_span");
+        literal.span = *self.span_interner.get(span.0);
     }
 
+    /// Range is in bytes of the literal as it is written in the source, like in rustc: it
+    /// includes the prefix (`b`, `r#`), quotes, escape sequences and suffix of the literal.
     fn subspan(
         &mut self,
-        _literal: &Self::Literal,
-        _start: Bound<usize>,
-        _end: Bound<usize>,
+        literal: &Self::Literal,
+        start: Bound<usize>,
+        end: Bound<usize>,
     ) -> Option<Self::Span> {
-        None // TODO add some sensible implementation
+        let _guard = BridgeMethodGuard::enter("Literal::subspan");
+        let text = &literal.text;
+        let span = literal.span;
+
+        // Literals created or respanned by the macro do not have their text in the input
+        let source_text = span.text(&self.source_map);
+        if span.origin != SpanOrigin::Input || source_text != Some(text.as_str()) {
+            return None;
+        }
+
+        let start = match start {
+            Bound::Included(start) => start,
+            Bound::Excluded(start) => start.checked_add(1)?,
+            Bound::Unbounded => 0,
+        };
+        let end = match end {
+            Bound::Included(end) => end.checked_add(1)?,
+            Bound::Excluded(end) => end,
+            Bound::Unbounded => text.len(),
+        };
+
+        // Spans inside of a char would break conversion to lines and columns
+        if start >= end || end > text.len() || !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+            return None;
+        }
+
+        Some(self.intern_span(MySpanData {
+            lo: span.lo + start as u32,
+            hi: span.lo + end as u32,
+            ..span
+        }))
     }
 }
 
 impl server::SourceFile for Rustc {
     fn eq(&mut self, file1: &Self::SourceFile, file2: &Self::SourceFile) -> bool {
+        let _guard = BridgeMethodGuard::enter("SourceFile::eq");
         file1.eq(file2)
     }
     fn path(&mut self, file: &Self::SourceFile) -> String {
-        //        match file.path() {
-        //            FileName::Real(ref path) => path
-        //                .to_str()
-        //                .expect("non-UTF8 file path in `proc_macro::SourceFile::path`")
-        //                .to_string(),
-        /*_ =>*/
-        //        }
-        String::from(
-            file.path()
-                .to_str()
-                .expect("non-UTF8 file path in `proc_macro::SourceFile::path`"),
-        )
+        let _guard = BridgeMethodGuard::enter("SourceFile::path");
+        self.source_map.path(file.file)
     }
     fn is_real(&mut self, file: &Self::SourceFile) -> bool {
-        file.is_real()
+        let _guard = BridgeMethodGuard::enter("SourceFile::is_real");
+        self.source_map.real_path(file.file).is_some()
     }
 }
 
 impl server::Diagnostic for Rustc {
     fn new(&mut self, level: Level, msg: &str, spans: Self::MultiSpan) -> Self::Diagnostic {
-        let mut diag = proc_macro::Diagnostic::new(level, msg);
-        diag.set_spans(spans);
-        diag
+        let _guard = BridgeMethodGuard::enter("Diagnostic::new");
+        MyDiagnostic {
+            level,
+            message: msg.to_string(),
+            spans: spans.spans,
+            children: vec![],
+        }
     }
     //    fn new_span(&mut self, level: Level, msg: &str, span: Self::Span) -> Self::Diagnostic {
     ////        let MySpanData(span) = *self.span_interner.get(span.0);
@@ -586,14 +969,10 @@ impl server::Diagnostic for Rustc {
     //        unimplemented!("new_span")
     //    }
 
-    fn sub(
-        &mut self,
-        _diag: &mut Self::Diagnostic,
-        _level: Level,
-        _msg: &str,
-        _spans: Self::MultiSpan,
-    ) {
-        unimplemented!("No sub method on proc_macro::Diagnostic")
+    fn sub(&mut self, diag: &mut Self::Diagnostic, level: Level, msg: &str, spans: Self::MultiSpan) {
+        let _guard = BridgeMethodGuard::enter("Diagnostic::sub");
+        let child = <Self as server::Diagnostic>::new(self, level, msg, spans);
+        diag.children.push(child)
     }
 
     //    fn sub_span(&mut self, diag: &mut Self::Diagnostic, level: Level, msg: &str, span: Self::Span) {
@@ -601,95 +980,124 @@ impl server::Diagnostic for Rustc {
     //    }
 
     fn emit(&mut self, diag: Self::Diagnostic) {
-        diag.emit()
+        let _guard = BridgeMethodGuard::enter("Diagnostic::emit");
+        self.diagnostics.borrow_mut().push(diag)
     }
 }
 
 impl server::Span for Rustc {
     fn debug(&mut self, span: Self::Span) -> String {
-        let MySpanData(span) = self.span_interner.get(span.0);
-        format!("{:?}", span)
+        let _guard = BridgeMethodGuard::enter("Span::debug");
+        let span = self.span_interner.get(span.0);
+        format!("bytes({}..{})", span.lo, span.hi)
     }
+    /// Location of the macro definition is unknown, so the call site location is used.
     fn def_site(&mut self) -> Self::Span {
-        MySpan(
-            self.span_interner
-                .intern(&MySpanData(proc_macro2::Span::def_site())),
-        )
+        let _guard = BridgeMethodGuard::enter("Span::def_site");
+        self.intern_span(MySpanData {
+            origin: SpanOrigin::DefSite,
+            ctx: SyntaxContext::DefSite,
+            ..self.call_site
+        })
     }
     fn call_site(&mut self) -> Self::Span {
-        MySpan(
-            self.span_interner
-                .intern(&MySpanData(proc_macro2::Span::call_site())),
-        )
+        let _guard = BridgeMethodGuard::enter("Span::call_site");
+        self.intern_span(self.call_site)
     }
     fn source_file(&mut self, span: Self::Span) -> Self::SourceFile {
-        let MySpanData(span) = self.span_interner.get(span.0);
-        span.source_file()
+        let _guard = BridgeMethodGuard::enter("Span::source_file");
+        SourceFile {
+            file: self.span_interner.get(span.0).file,
+        }
     }
 
-    /// Recent feature, not yet in the proc_macro2
-    ///
-    /// See PR:
-    /// https://github.com/rust-lang/rust/pull/55780
-    fn source_text(&mut self, _span: Self::Span) -> Option<String> {
-        None
-    }
+    /// Text of the input the span points to. Definition site of the macro is not known, so
+    /// its text is not available.
+    /// Only spans of the input tokens have a text; spans created by the macro (including the
+    /// call site span) do not, even though they point into the input. Use `source` to get the
+    /// text of the invocation they are expanded from.
+    fn source_text(&mut self, span: Self::Span) -> Option<String> {
+        let _guard = BridgeMethodGuard::enter("Span::source_text");
+        let span = *self.span_interner.get(span.0);
 
-    fn parent(&mut self, _span: Self::Span) -> Option<Self::Span> {
-        //        let MySpanData(span) = *self.span_interner.get(span.0);
-        //        if let Some(span) = span.parent() {
-        //            return Some(MySpan(self.span_interner.intern(&MySpanData(span))))
-        //        }
+        match span.origin {
+            SpanOrigin::Input => span.text(&self.source_map).map(|text| text.to_string()),
 
-        None
+            SpanOrigin::CallSite | SpanOrigin::DefSite | SpanOrigin::MixedSite => None,
+        }
     }
+
+    /// Spans created by the macro are expanded from the macro invocation, while spans of the
+    /// input come from the user's code and have no parent.
+    ///
+    /// Expansions which have produced the input are not known to the expander, so the chain
+    /// is never longer than that: input tokens created by another macro have no parent too.
+    fn parent(&mut self, span: Self::Span) -> Option<Self::Span> {
+        let _guard = BridgeMethodGuard::enter("Span::parent");
+        match self.span_interner.get(span.0).origin {
+            SpanOrigin::Input => None,
+            SpanOrigin::CallSite | SpanOrigin::DefSite | SpanOrigin::MixedSite => {
+                let invocation = self.invocation();
+                Some(self.intern_span(invocation))
+            }
+        }
+    }
+    /// Outermost parent of the span, i.e. its location in the user's code.
     fn source(&mut self, span: Self::Span) -> Self::Span {
-        //        let MySpanData(span) = *self.span_interner.get(span.0);
-        //
-        //        MySpan(self.span_interner.intern(&MySpanData(span.source())))
-        span
+        let _guard = BridgeMethodGuard::enter("Span::source");
+        match <Self as server::Span>::parent(self, span) {
+            Some(parent) => <Self as server::Span>::source(self, parent),
+            None => span,
+        }
     }
     fn start(&mut self, span: Self::Span) -> LineColumn {
-        let MySpanData(span) = *self.span_interner.get(span.0);
+        let _guard = BridgeMethodGuard::enter("Span::start");
+        let span = *self.span_interner.get(span.0);
+        let (line, column) = self.source_map.line_column(span.file, span.lo);
 
-        //        span.start()
-        span.unstable().start()
+        LineColumn { line, column }
     }
     fn end(&mut self, span: Self::Span) -> LineColumn {
-        let MySpanData(span) = *self.span_interner.get(span.0);
+        let _guard = BridgeMethodGuard::enter("Span::end");
+        let span = *self.span_interner.get(span.0);
+        let (line, column) = self.source_map.line_column(span.file, span.hi);
 
-        span.unstable().end()
+        LineColumn { line, column }
     }
     fn join(&mut self, first: Self::Span, second: Self::Span) -> Option<Self::Span> {
-        let MySpanData(first) = *self.span_interner.get(first.0);
-        let MySpanData(second) = *self.span_interner.get(second.0);
+        let _guard = BridgeMethodGuard::enter("Span::join");
+        let first = *self.span_interner.get(first.0);
+        let second = *self.span_interner.get(second.0);
 
-        if let Some(join) = first.join(second) {
-            return Some(MySpan(self.span_interner.intern(&MySpanData(join))));
-        }
-
-        None
+        first.join(second).map(|join| self.intern_span(join))
     }
     fn resolved_at(&mut self, span: Self::Span, at: Self::Span) -> Self::Span {
-        let MySpanData(span) = *self.span_interner.get(span.0);
-        let MySpanData(at) = *self.span_interner.get(at.0);
-        let resolved_at = span.resolved_at(at);
+        let _guard = BridgeMethodGuard::enter("Span::resolved_at");
+        let span = *self.span_interner.get(span.0);
+        let at = *self.span_interner.get(at.0);
 
-        MySpan(self.span_interner.intern(&MySpanData(resolved_at)))
+        self.intern_span(MySpanData { ctx: at.ctx, ..span })
     }
 
     fn mixed_site(&mut self) -> Self::Span {
-        unimplemented!("Span::mixed_state is not yet implemented in proc_macro2")
+        let _guard = BridgeMethodGuard::enter("Span::mixed_site");
+        self.intern_span(MySpanData {
+            origin: SpanOrigin::MixedSite,
+            ctx: SyntaxContext::MixedSite,
+            ..self.call_site
+        })
     }
 }
 
 impl server::MultiSpan for Rustc {
     fn new(&mut self) -> Self::MultiSpan {
-        unimplemented!("MultiSpan::new is not implemented");
+        let _guard = BridgeMethodGuard::enter("MultiSpan::new");
+        MultiSpan::default()
     }
 
-    fn push(&mut self, _other: &mut Self::MultiSpan, _span: Self::Span) {
-        unimplemented!("MultiSpan::new is not implemented");
+    fn push(&mut self, spans: &mut Self::MultiSpan, span: Self::Span) {
+        let _guard = BridgeMethodGuard::enter("MultiSpan::push");
+        spans.spans.push(*self.span_interner.get(span.0))
     }
 }
 
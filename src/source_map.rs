@@ -0,0 +1,117 @@
+//! Texts of the macro inputs, which spans of the input tokens point into.
+
+use std::path::PathBuf;
+
+use macro_expansion::{InputKind, SourceLocation};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct FileId(u32);
+
+struct SourceFileData {
+    kind: InputKind,
+    text: String,
+    /// Position of the text in the user's file, if it is known.
+    location: Option<SourceLocation>,
+    /// Byte offsets of the starts of lines of the text.
+    line_starts: Vec<usize>,
+}
+
+/// Input texts of a single expansion.
+///
+/// Spans store byte ranges of these texts; positions in the user's files are computed from
+/// `SourceLocation` of the text only when requested.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFileData>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Adds the text, which is located in the user's file at the `location` (if known).
+    pub fn add(&mut self, kind: InputKind, text: &str, location: Option<&SourceLocation>) -> FileId {
+        let line_starts = Some(0)
+            .into_iter()
+            .chain(text.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+
+        self.files.push(SourceFileData {
+            kind,
+            text: text.to_string(),
+            location: location.cloned(),
+            line_starts,
+        });
+
+        FileId(self.files.len() as u32 - 1)
+    }
+
+    fn file(&self, file: FileId) -> &SourceFileData {
+        &self.files[file.0 as usize]
+    }
+
+    pub fn text(&self, file: FileId) -> &str {
+        &self.file(file).text
+    }
+
+    pub fn kind(&self, file: FileId) -> InputKind {
+        self.file(file).kind
+    }
+
+    /// Path of the user's file, or a name of the text if it is not located in a real file.
+    pub fn path(&self, file: FileId) -> String {
+        let file = self.file(file);
+        match (&file.location, file.kind) {
+            (Some(location), _) => location.file.display().to_string(),
+            (None, InputKind::MacroBody) => "<macro_body>".to_string(),
+            (None, InputKind::Attributes) => "<attributes>".to_string(),
+        }
+    }
+
+    pub fn real_path(&self, file: FileId) -> Option<&PathBuf> {
+        self.file(file).location.as_ref().map(|location| &location.file)
+    }
+
+    /// Byte offset of `pos` of the text in the user's file, or in the text itself if its
+    /// location is unknown.
+    pub fn file_offset(&self, file: FileId, pos: u32) -> usize {
+        let file = self.file(file);
+        file.location.as_ref().map_or(0, |location| location.offset) + pos as usize
+    }
+
+    /// Line (starting from 1) and column (in chars, starting from 0) of `pos` of the text in the
+    /// user's file. Positions inside a char are counted as the start of the char.
+    pub fn line_column(&self, file: FileId, pos: u32) -> (usize, usize) {
+        let file = self.file(file);
+        let mut pos = std::cmp::min(pos as usize, file.text.len());
+        while !file.text.is_char_boundary(pos) {
+            pos -= 1;
+        }
+
+        let line = match file.line_starts.binary_search(&pos) {
+            Ok(line) => line,
+            Err(next_line) => next_line - 1,
+        };
+        let column = file.text[file.line_starts[line]..pos].chars().count();
+
+        match &file.location {
+            Some(location) if line == 0 => (location.line, location.column + column),
+            Some(location) => (location.line + line, column),
+            None => (line + 1, column),
+        }
+    }
+
+    /// Byte position in the text of the `line` (starting from 1) and `column` (in bytes,
+    /// starting from 0, as proc_macro2 counts them), counted from the start of the text.
+    pub fn position(&self, file: FileId, line: usize, column: usize) -> u32 {
+        let file = self.file(file);
+
+        let line_start = match file.line_starts.get(line.saturating_sub(1)) {
+            Some(start) => *start,
+            None => return file.text.len() as u32,
+        };
+
+        std::cmp::min(line_start + column, file.text.len()) as u32
+    }
+}
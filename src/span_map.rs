@@ -0,0 +1,74 @@
+//! Conversion of the spans of the expansion to the results: origins of the expansion tokens,
+//! reported with `ExpansionTask::span_map`, and spans of the diagnostics.
+
+use macro_expansion::{Diagnostic, DiagnosticLevel, Hygiene, TokenSource, TokenSpan};
+use proc_macro::Level;
+use rustc_server::{MyDiagnostic, MySpanData, SpanOrigin, SyntaxContext, TokenStream};
+use source_map::SourceMap;
+
+fn token_source(span: MySpanData, source_map: &SourceMap) -> TokenSource {
+    match span.origin {
+        SpanOrigin::Input => TokenSource::Input {
+            input: source_map.kind(span.file),
+            start: source_map.file_offset(span.file, span.lo),
+            end: source_map.file_offset(span.file, span.hi),
+        },
+
+        SpanOrigin::CallSite => TokenSource::CallSite,
+
+        SpanOrigin::DefSite => TokenSource::DefSite,
+
+        SpanOrigin::MixedSite => TokenSource::MixedSite,
+    }
+}
+
+fn hygiene(span: MySpanData) -> Hygiene {
+    match span.ctx {
+        SyntaxContext::CallSite => Hygiene::CallSite,
+        SyntaxContext::DefSite => Hygiene::DefSite,
+        SyntaxContext::MixedSite => Hygiene::MixedSite,
+    }
+}
+
+/// Formats the expansion, reporting the origin of each of its tokens.
+pub fn print_with_spans(expansion: &TokenStream, source_map: &SourceMap) -> (String, Vec<TokenSpan>) {
+    let mut text = String::new();
+    let mut tokens = vec![];
+
+    expansion.print(source_map, &mut text, &mut |range, span| {
+        tokens.push(TokenSpan {
+            start: range.start,
+            end: range.end,
+            source: token_source(span, source_map),
+            hygiene: hygiene(span),
+        })
+    });
+
+    (text, tokens)
+}
+
+pub fn diagnostic(diagnostic: &MyDiagnostic, source_map: &SourceMap) -> Diagnostic {
+    let level = match diagnostic.level {
+        Level::Error => DiagnosticLevel::Error,
+        Level::Warning => DiagnosticLevel::Warning,
+        Level::Note => DiagnosticLevel::Note,
+        Level::Help => DiagnosticLevel::Help,
+        // Levels added in the future are most likely less severe than errors
+        _ => DiagnosticLevel::Note,
+    };
+
+    Diagnostic {
+        level,
+        message: diagnostic.message.clone(),
+        spans: diagnostic
+            .spans
+            .iter()
+            .map(|span| token_source(*span, source_map))
+            .collect(),
+        children: diagnostic
+            .children
+            .iter()
+            .map(|child| self::diagnostic(child, source_map))
+            .collect(),
+    }
+}
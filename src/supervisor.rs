@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use macro_expansion::{ErrorCode, ExpansionRequest, ExpansionResult, ExpansionTask, ResourceLimits};
+use {error_result, resource_limits, ExpansionError};
+
+/// How long to wait for the rest of stderr of a dead worker.
+const STDERR_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Forwards stderr of the worker to our stderr, noting whether it has reported a failed allocation.
+///
+/// Returned receiver is disconnected once the whole stderr is forwarded.
+fn forward_stderr(stderr: ChildStderr, allocation_failed: Arc<AtomicBool>) -> Receiver<()> {
+    let (sender, receiver) = mpsc::channel::<()>();
+
+    thread::spawn(move || {
+        let _sender = sender;
+
+        for line in BufReader::new(stderr).split(b'\n') {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            if resource_limits::is_allocation_failure(&String::from_utf8_lossy(&line)) {
+                allocation_failed.store(true, Ordering::SeqCst);
+            }
+
+            let stderr = io::stderr();
+            let mut stderr = stderr.lock();
+            let _ = stderr.write_all(&line).and_then(|()| stderr.write_all(b"\n"));
+        }
+    });
+
+    receiver
+}
+
+/// Child expander process, launched in the server mode as a worker.
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    allocation_failed: Arc<AtomicBool>,
+    stderr_forwarded: Receiver<()>,
+}
+
+impl Worker {
+    fn spawn(exe: &PathBuf) -> io::Result<Worker> {
+        let mut child = Command::new(exe)
+            .arg("--server")
+            .arg("--worker")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.take().expect("Worker stdin should be piped");
+        let stdout = child.stdout.take().expect("Worker stdout should be piped");
+        let stderr = child.stderr.take().expect("Worker stderr should be piped");
+
+        let allocation_failed = Arc::new(AtomicBool::new(false));
+        let stderr_forwarded = forward_stderr(stderr, allocation_failed.clone());
+
+        Ok(Worker {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+            allocation_failed,
+            stderr_forwarded,
+        })
+    }
+
+    fn send(&mut self, request: &ExpansionRequest) -> io::Result<()> {
+        let request = serde_json::to_string(request)?;
+        writeln!(self.stdin, "{}", request)?;
+        self.stdin.flush()
+    }
+
+    /// Sends the task to the worker and waits for the result.
+    ///
+    /// `None` means that the worker has died without answering, or that it has answered with
+    /// something other than the result of this task.
+    fn expand(&mut self, task: &ExpansionTask) -> Option<ExpansionResult> {
+        self.send(&ExpansionRequest::Expand(task.clone())).ok()?;
+
+        let mut line = String::new();
+        match self.stdout.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => serde_json::from_str::<ExpansionResult>(&line)
+                .ok()
+                .filter(|result| result.id() == task.id.as_ref().map(|id| id.as_str())),
+        }
+    }
+
+    /// Waits for the dead worker and finds out why it has exited.
+    fn crash_error(&mut self, limits: &ResourceLimits) -> ExpansionError {
+        // Worker might be still alive if it has sent something unexpected
+        let _ = self.child.kill();
+
+        let status = match self.child.wait() {
+            Ok(status) => status,
+            Err(err) => {
+                let message = format!("worker process has exited unexpectedly: {}", err);
+                return ExpansionError::WorkerCrashed { message };
+            }
+        };
+
+        // Stderr might be still inherited by the children of the worker, so it is not awaited forever
+        let _ = self.stderr_forwarded.recv_timeout(STDERR_DRAIN_TIMEOUT);
+        let allocation_failed = self.allocation_failed.load(Ordering::SeqCst);
+
+        if let Some(error) = resource_limits::exceeded_limit(status, limits, allocation_failed) {
+            return error;
+        }
+
+        let message = format!("worker process has exited unexpectedly ({})", status);
+        ExpansionError::WorkerCrashed { message }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if self.send(&ExpansionRequest::Shutdown).is_err() {
+            let _ = self.child.kill();
+        }
+
+        let _ = self.child.wait();
+    }
+}
+
+/// Performs expansions in child worker processes, one worker per set of libraries.
+///
+/// Libraries are loaded only by workers, so a macro that crashes or exits takes down only its
+/// worker. Such tasks are reported as `worker_crashed` errors, and the worker is transparently
+/// restarted for the next task. Workers which have timed out are restarted as well, since the
+/// stuck macro is still running inside of them.
+///
+/// Workers apply `ResourceLimits` of each task to themselves, so a breached limit kills only the
+/// worker, and it is reported as a distinct error.
+pub struct Supervisor {
+    worker_exe: PathBuf,
+    workers: HashMap<Vec<PathBuf>, Worker>,
+}
+
+impl Supervisor {
+    /// `worker_exe` is a `proc_macro_expander` executable, which is launched in the server mode.
+    pub fn new(worker_exe: PathBuf) -> Supervisor {
+        Supervisor {
+            worker_exe,
+            workers: HashMap::new(),
+        }
+    }
+
+    pub fn expand(&mut self, task: &ExpansionTask) -> ExpansionResult {
+        if !self.workers.contains_key(&task.libs) {
+            match Worker::spawn(&self.worker_exe) {
+                Ok(worker) => {
+                    self.workers.insert(task.libs.clone(), worker);
+                }
+
+                Err(err) => {
+                    let message = format!("cannot start worker process: {}", err);
+                    return error_result(task, ExpansionError::WorkerCrashed { message });
+                }
+            }
+        }
+
+        let result = self
+            .workers
+            .get_mut(&task.libs)
+            .expect("Worker should be started")
+            .expand(task);
+
+        match result {
+            Some(result) => {
+                if let ExpansionResult::Error { code: ErrorCode::Timeout, .. } = result {
+                    // Stuck macro is still running inside of the worker, so it is replaced
+                    self.workers.remove(&task.libs);
+                }
+
+                result
+            }
+
+            None => {
+                let mut worker = self.workers.remove(&task.libs).expect("Worker should be started");
+                let error = worker.crash_error(&task.limits);
+                error_result(task, error)
+            }
+        }
+    }
+}
@@ -2,12 +2,17 @@ extern crate proc_macro_expander;
 extern crate tempfile;
 #[macro_use]
 extern crate assert_matches;
+#[macro_use]
+extern crate lazy_static;
 
-use proc_macro_expander::macro_expansion::{ExpansionTask, ExpansionResult};
+use proc_macro_expander::macro_expansion::{
+    ExpansionTask, ExpansionResult, ExpansionRequest, ErrorCode, ResourceLimits, ExportedMacro,
+    MacroKind, SourceLocation, TokenSpan, TokenSource, Hygiene, InputKind, Diagnostic, DiagnosticLevel,
+};
 
-use std::fs::{canonicalize, create_dir, File};
+use std::fs::{canonicalize, create_dir, create_dir_all, File};
 use std::{io, fs};
-use std::io::Write;
+use std::io::{BufRead, BufReader, Write};
 use std::io::ErrorKind;
 use std::path::{PathBuf, Path};
 use std::process::{Command, Stdio};
@@ -72,25 +77,306 @@ proc-macro = true
     "#
     )?;
 
-    create_dir(root_dir.join("src"))?;
+    create_dir_all(root_dir.join("src"))?;
     let mut lib_file = File::create(root_dir.join("src").join("lib.rs"))?;
     write!(
         &mut lib_file,
         "{}",
         r#"
+#![feature(proc_macro_span)]
+#![feature(proc_macro_def_site)]
+#![feature(proc_macro_diagnostic)]
+#![feature(proc_macro_mixed_site)]
+#![feature(proc_macro_raw_ident)]
 extern crate proc_macro;
 
-use proc_macro::TokenStream;
+use proc_macro::{Diagnostic, Group, Ident, Level, Literal, Punct, Span, TokenStream, TokenTree};
 
 #[proc_macro]
 pub fn id_macro(input: TokenStream) -> TokenStream {
     input
 }
 
+#[proc_macro]
+pub fn printing_macro(input: TokenStream) -> TokenStream {
+    println!("{{\"type\":\"success\",\"expansion\":\"printed\"}}");
+    print!("not a response");
+    input
+}
+
 #[proc_macro]
 pub fn make_answer_macro(input: TokenStream) -> TokenStream {
     "fn answer() -> u32 { 42 }".parse().unwrap()
 }
+
+#[proc_macro]
+pub fn panicking_macro(input: TokenStream) -> TokenStream {
+    panic!("panicking_macro is always panicking")
+}
+
+#[proc_macro]
+pub fn exiting_macro(input: TokenStream) -> TokenStream {
+    std::process::exit(13)
+}
+
+#[proc_macro]
+pub fn aborting_macro(input: TokenStream) -> TokenStream {
+    std::process::abort()
+}
+
+#[proc_macro]
+pub fn looping_macro(input: TokenStream) -> TokenStream {
+    loop {}
+}
+
+#[proc_macro]
+pub fn allocating_macro(input: TokenStream) -> TokenStream {
+    let memory = vec![1u8; 1 << 30];
+    memory.len().to_string().parse().unwrap()
+}
+
+static EXPANSIONS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Expands to the number of its expansions in the loaded library.
+#[proc_macro]
+pub fn counting_macro(input: TokenStream) -> TokenStream {
+    let count = EXPANSIONS.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+    format!("const COUNT: usize = {};", count).parse().unwrap()
+}
+
+/// Expands to a string literal with locations of the input tokens.
+#[proc_macro]
+pub fn span_positions_macro(input: TokenStream) -> TokenStream {
+    let positions: Vec<String> = input
+        .into_iter()
+        .map(|tree| {
+            let span = tree.span();
+            format!(
+                "{}:{}:{}-{}:{}",
+                span.source_file().path().display(),
+                span.start().line,
+                span.start().column,
+                span.end().line,
+                span.end().column
+            )
+        })
+        .collect();
+
+    format!("{:?}", positions.join(" ")).parse().unwrap()
+}
+
+/// Expands to a string literal with locations of the attribute tokens.
+#[proc_macro_attribute]
+pub fn span_positions_attribute(attr: TokenStream, item: TokenStream) -> TokenStream {
+    span_positions_macro(attr)
+}
+
+/// Surrounds the input with tokens created at the call site and at the definition site.
+#[proc_macro]
+pub fn site_spans_macro(input: TokenStream) -> TokenStream {
+    let mut output: TokenStream = "let".parse().unwrap();
+    output.extend(input);
+    output.extend(vec![TokenTree::from(Ident::new("def", Span::def_site()))]);
+    output
+}
+
+#[proc_macro]
+pub fn hygiene_macro(input: TokenStream) -> TokenStream {
+    let first = input.into_iter().next().expect("Input should not be empty");
+    vec![
+        Ident::new("mixed", Span::mixed_site()),
+        Ident::new("def", Span::def_site()),
+        Ident::new("call", Span::call_site()),
+        Ident::new("resolved", first.span().resolved_at(Span::def_site())),
+    ]
+    .into_iter()
+    .map(TokenTree::from)
+    .collect()
+}
+
+#[proc_macro]
+pub fn literal_subspan_macro(input: TokenStream) -> TokenStream {
+    for token in input {
+        if let TokenTree::Literal(literal) = token {
+            let text = literal.to_string();
+            let x = text.rfind('x').expect("Literal should contain 'x'");
+            literal.subspan(x..x + 1).expect("Cannot get subspan").warning("x").emit();
+
+            if literal.subspan(..text.len() + 1).is_some() {
+                Span::call_site().error("subspan is out of the literal").emit();
+            }
+            if text.contains('é') && literal.subspan(1..2).is_some() {
+                Span::call_site().error("subspan splits a char").emit();
+            }
+
+            let mut respanned = Literal::character('y');
+            respanned.set_span(literal.span());
+            if respanned.subspan(1..2).is_some() {
+                Span::call_site().error("subspan of a respanned literal").emit();
+            }
+        }
+    }
+    TokenStream::new()
+}
+
+#[proc_macro]
+pub fn source_text_macro(input: TokenStream) -> TokenStream {
+    let first = input.clone().into_iter().next().expect("Input should not be empty");
+    let mut spans: Vec<Span> = input.into_iter().map(|token| token.span()).collect();
+    spans.push(Span::call_site());
+    spans.push(Span::def_site());
+    spans.push(Span::call_site().source());
+    spans.extend(first.span().parent());
+
+    spans
+        .into_iter()
+        .map(|span| span.source_text().unwrap_or("<none>".to_string()))
+        .map(|text| TokenTree::from(Literal::string(&text)))
+        .collect()
+}
+
+#[proc_macro]
+pub fn numeric_literals_macro(_input: TokenStream) -> TokenStream {
+    vec![
+        Literal::isize_suffixed(-1),
+        Literal::usize_unsuffixed(7),
+        Literal::u128_suffixed(u128::max_value()),
+        Literal::i128_suffixed(i128::min_value()),
+        Literal::i8_unsuffixed(-128),
+        Literal::f32_suffixed(1e10),
+        Literal::f64_unsuffixed(0.25),
+        Literal::f64_suffixed(-2.5),
+    ]
+    .into_iter()
+    .map(TokenTree::from)
+    .collect()
+}
+
+#[proc_macro]
+pub fn raw_ident_macro(input: TokenStream) -> TokenStream {
+    let mut output: Vec<TokenTree> = input.into_iter().collect();
+    output.push(Ident::new_raw("match", Span::call_site()).into());
+    output.push(Ident::new("fn", Span::call_site()).into());
+    output.into_iter().collect()
+}
+
+#[proc_macro]
+pub fn invalid_ident_macro(_input: TokenStream) -> TokenStream {
+    TokenTree::from(Ident::new("not an ident", Span::call_site())).into()
+}
+
+#[proc_macro]
+pub fn invalid_parse_macro(_input: TokenStream) -> TokenStream {
+    match "fn (".parse::<TokenStream>() {
+        Ok(stream) => stream,
+        Err(_) => TokenStream::new(),
+    }
+}
+
+fn check_span_identity(span: Span) {
+    let def_site = span.resolved_at(Span::def_site());
+    let checks = [
+        span.resolved_at(span).eq(&span),
+        span.located_at(span).eq(&span),
+        span.join(span).map_or(false, |joined| joined.eq(&span)),
+        !def_site.eq(&span),
+        def_site.resolved_at(Span::call_site()).eq(&span),
+    ];
+
+    if checks.iter().any(|check| !check) {
+        span.error("span identity is not preserved").emit();
+    }
+}
+
+/// Recreates each token of the stream with the span of the original one.
+fn respan(stream: TokenStream) -> TokenStream {
+    stream
+        .into_iter()
+        .map(|token| {
+            let span = token.span();
+            check_span_identity(span);
+
+            let mut respanned = match token {
+                TokenTree::Group(group) => {
+                    TokenTree::from(Group::new(group.delimiter(), respan(group.stream())))
+                }
+                TokenTree::Ident(ident) => {
+                    let name = ident.to_string();
+                    if name.starts_with("r#") {
+                        Ident::new_raw(&name[2..], Span::call_site()).into()
+                    } else {
+                        Ident::new(&name, Span::call_site()).into()
+                    }
+                }
+                TokenTree::Punct(punct) => Punct::new(punct.as_char(), punct.spacing()).into(),
+                TokenTree::Literal(literal) => {
+                    let stream: TokenStream = literal.to_string().parse().unwrap();
+                    stream.into_iter().next().unwrap()
+                }
+            };
+
+            respanned.set_span(span);
+            respanned
+        })
+        .collect()
+}
+
+#[proc_macro]
+pub fn respan_macro(input: TokenStream) -> TokenStream {
+    respan(input)
+}
+
+#[proc_macro]
+pub fn diagnostics_macro(input: TokenStream) -> TokenStream {
+    let first = input.clone().into_iter().next().expect("Input should not be empty");
+    first.span().warning("first token").emit();
+    Span::call_site().error("whole input").emit();
+    input
+}
+
+#[proc_macro]
+pub fn diagnostics_panicking_macro(input: TokenStream) -> TokenStream {
+    Span::call_site().error("about to panic").emit();
+    panic!("diagnostics_panicking_macro is always panicking")
+}
+
+#[proc_macro]
+pub fn diagnostic_tree_macro(input: TokenStream) -> TokenStream {
+    let tokens: Vec<TokenTree> = input.clone().into_iter().collect();
+    Diagnostic::spanned(vec![tokens[0].span(), tokens[1].span()], Level::Error, "multi-span error")
+        .span_note(tokens[1].span(), "note on the second token")
+        .help("help without spans")
+        .emit();
+    input
+}
+
+#[proc_macro]
+pub fn compile_error_macro(input: TokenStream) -> TokenStream {
+    let span = input.into_iter().next().expect("Input should not be empty").span();
+    let error: TokenStream = "compile_error!(\"expected \\\"fn\\\"\");".parse().unwrap();
+    error
+        .into_iter()
+        .map(|mut token| {
+            token.set_span(span);
+            token
+        })
+        .collect()
+}
+
+#[proc_macro_derive(HelperDerive, attributes(helper, other_helper))]
+pub fn helper_derive(input: TokenStream) -> TokenStream {
+    TokenStream::new()
+}
+
+#[proc_macro_derive(EchoInput)]
+pub fn echo_input(input: TokenStream) -> TokenStream {
+    format!("const INPUT: &str = {:?};", input.to_string()).parse().unwrap()
+}
+
+#[proc_macro_attribute]
+pub fn id_attribute(attr: TokenStream, item: TokenStream) -> TokenStream {
+    item
+}
     "#
     )?;
 
@@ -133,7 +419,31 @@ fn compile_proc_macro(dir: &Path, proc_macro_name: &str) -> io::Result<PathBuf>
     }
 }
 
-fn perform_expansion(task: ExpansionTask) -> io::Result<ExpansionResult> {
+lazy_static! {
+    /// Library of the test project, which is compiled only once for all the tests.
+    static ref TEST_PROC_MACRO: PathBuf = {
+        let dir = proc_macro_expander_exe()
+            .expect("Cannot find expander")
+            .with_file_name("test_proc_macro");
+
+        create_dir_all(&dir).expect("Cannot create test project dir");
+        setup_proc_macro_project(&dir).expect("Cannot setup test project");
+        compile_proc_macro(&dir, "test_proc_macro").expect("Cannot find proc macro!")
+    };
+}
+
+/// Task expanding a macro of the test project without attributes.
+fn test_task(macro_name: &str, macro_body: &str) -> ExpansionTask {
+    ExpansionTask {
+        libs: vec![TEST_PROC_MACRO.clone()],
+        macro_body: macro_body.to_string(),
+        macro_name: macro_name.into(),
+        attributes: None,
+        ..Default::default()
+    }
+}
+
+fn perform_expansion(mut task: ExpansionTask) -> io::Result<ExpansionResult> {
     let expander = proc_macro_expander_exe()?;
 
     let mut result = Command::new(expander)
@@ -141,6 +451,9 @@ fn perform_expansion(task: ExpansionTask) -> io::Result<ExpansionResult> {
         .stdout(Stdio::piped())
         .spawn()?;
 
+    let task_id = "perform_expansion".to_string();
+    task.id = Some(task_id.clone());
+
     write!(
         result.stdin.as_mut().unwrap(),
         "{}",
@@ -151,12 +464,69 @@ fn perform_expansion(task: ExpansionTask) -> io::Result<ExpansionResult> {
 
     let results: Vec<ExpansionResult> = serde_json::from_reader(result.stdout.unwrap())?;
 
-    // FIXME this is terrible
-    Ok(results.into_iter().nth(0).expect(
-        &format!("Expansion results for task {:?} are empty!", &task)
+    Ok(results.into_iter().find(|result| result.id() == Some(task_id.as_str())).expect(
+        &format!("No expansion result for task {:?}!", &task)
     ))
 }
 
+fn perform_server_expansions(requests: &[ExpansionRequest]) -> io::Result<Vec<ExpansionResult>> {
+    perform_server_expansions_with_args(requests, &[])
+}
+
+fn perform_server_expansions_with_args(
+    requests: &[ExpansionRequest],
+    args: &[&str],
+) -> io::Result<Vec<ExpansionResult>> {
+    let expander = proc_macro_expander_exe()?;
+
+    let mut server = Command::new(expander)
+        .arg("--server")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    {
+        // Closing stdin makes the server exit after the last request
+        let mut stdin = server.stdin.take().unwrap();
+        for request in requests {
+            writeln!(stdin, "{}", &serde_json::to_string(request)?)?;
+        }
+    }
+
+    let mut results = vec![];
+    for line in BufReader::new(server.stdout.take().unwrap()).lines() {
+        results.push(serde_json::from_str(&line?)?);
+    }
+
+    server.wait()?;
+
+    Ok(results)
+}
+
+#[cfg(target_os = "linux")]
+fn resident_memory_kb(pid: u32) -> io::Result<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+
+    status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Cannot find VmRSS"))
+}
+
+fn list_macros(libs: &[PathBuf]) -> io::Result<Vec<ExportedMacro>> {
+    let expander = proc_macro_expander_exe()?;
+
+    let output = Command::new(expander)
+        .arg("list")
+        .args(libs)
+        .output()?;
+
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
 #[test]
 fn test_simple_bang_proc_macros() -> io::Result<()> {
     let tmp_dir = TempDir::new().expect("Cannot create temp dir");
@@ -168,8 +538,9 @@ fn test_simple_bang_proc_macros() -> io::Result<()> {
         let id_macro_task = ExpansionTask {
             libs: vec![proc_macro_dyn_lib.clone()],
             macro_body: "struct S {}".to_string(),
-            macro_name: "id_macro".to_string(),
+            macro_name: "id_macro".into(),
             attributes: None,
+            ..Default::default()
         };
 
         let id_macro_expansion = perform_expansion(id_macro_task).expect(
@@ -178,7 +549,7 @@ fn test_simple_bang_proc_macros() -> io::Result<()> {
 
         assert_matches!(
             id_macro_expansion,
-            ExpansionResult::Success { ref expansion } if expansion.contains("struct S")
+            ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
         );
     }
 
@@ -186,8 +557,9 @@ fn test_simple_bang_proc_macros() -> io::Result<()> {
         let make_answer_macro_task = ExpansionTask {
             libs: vec![proc_macro_dyn_lib.clone()],
             macro_body: "".to_string(),
-            macro_name: "make_answer_macro".to_string(),
+            macro_name: "make_answer_macro".into(),
             attributes: None,
+            ..Default::default()
         };
 
         let make_answer_macro_expansion = perform_expansion(make_answer_macro_task).expect(
@@ -196,7 +568,7 @@ fn test_simple_bang_proc_macros() -> io::Result<()> {
 
         assert_matches!(
             make_answer_macro_expansion,
-            ExpansionResult::Success { ref expansion } if expansion.contains("fn answer")
+            ExpansionResult::Success { ref expansion, .. } if expansion.contains("fn answer")
         );
     }
 
@@ -215,8 +587,9 @@ fn test_proc_macro_libraries() {
         let expansion_task = ExpansionTask {
             libs: vec![getset_lib.clone()],
             macro_body: "struct S { #[set] y: i32 }".to_string(),
-            macro_name: "Setters".to_string(),
+            macro_name: "Setters".into(),
             attributes: None,
+            ..Default::default()
         };
 
         let expansion_result = perform_expansion(expansion_task).expect(
@@ -225,9 +598,849 @@ fn test_proc_macro_libraries() {
 
         assert_matches!(
             expansion_result,
-            ExpansionResult::Success { ref expansion }
+            ExpansionResult::Success { ref expansion, .. }
             if expansion.contains("fn set_y")
         );
     }
+
+    {
+        let expansion_task = ExpansionTask {
+            libs: vec![getset_lib.clone()],
+            macro_body: "struct S { #[get] #[set] y: i32 }".to_string(),
+            macro_name: vec!["Getters".to_string(), "Setters".to_string()].into(),
+            attributes: None,
+            ..Default::default()
+        };
+
+        let expansion_result = perform_expansion(expansion_task).expect(
+            "Cannot perform expansion for 'Getters, Setters'"
+        );
+
+        let parts = match expansion_result {
+            ExpansionResult::Success { ref expansion, ref parts, .. } => {
+                assert!(expansion.contains("fn y") && expansion.contains("fn set_y"));
+                parts.clone()
+            }
+            _ => panic!("Expansion has failed: {:?}", expansion_result),
+        };
+
+        assert_eq!(parts.len(), 2);
+
+        assert_eq!(parts[0].macro_name, "Getters");
+        assert_eq!(parts[0].library, getset_lib);
+        assert!(parts[0].expansion.contains("fn y") && !parts[0].expansion.contains("fn set_y"));
+
+        assert_eq!(parts[1].macro_name, "Setters");
+        assert_eq!(parts[1].library, getset_lib);
+        assert!(parts[1].expansion.contains("fn set_y"));
+    }
+}
+
+#[test]
+fn test_server_mode() {
+    let requests = vec![
+        ExpansionRequest::Expand(ExpansionTask {
+            id: Some("first".to_string()),
+            ..test_task("id_macro", "struct S {}")
+        }),
+        ExpansionRequest::Expand(ExpansionTask {
+            id: Some("second".to_string()),
+            ..test_task("make_answer_macro", "")
+        }),
+        ExpansionRequest::Shutdown,
+        ExpansionRequest::Expand(test_task("id_macro", "struct Ignored {}")),
+    ];
+
+    let results = perform_server_expansions(&requests).expect("Cannot perform server expansions");
+
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].id(), Some("first"));
+    assert_eq!(results[1].id(), Some("second"));
+    assert_matches!(
+        results[0],
+        ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
+    );
+    assert_matches!(
+        results[1],
+        ExpansionResult::Success { ref expansion, .. } if expansion.contains("fn answer")
+    );
+}
+
+#[test]
+fn test_macro_output_is_not_mixed_with_responses() {
+    let task = |id: &str, macro_name: &str| ExpansionRequest::Expand(ExpansionTask {
+        id: Some(id.to_string()),
+        ..test_task(macro_name, "struct S {}")
+    });
+
+    let requests = vec![task("printing", "printing_macro"), task("id", "id_macro")];
+
+    for args in &[vec![], vec!["--isolate"]] {
+        let results = perform_server_expansions_with_args(&requests, args)
+            .expect("Cannot perform server expansions");
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].id(), Some("printing"));
+        assert_eq!(results[1].id(), Some("id"));
+        assert_matches!(
+            results[0],
+            ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
+        );
+        assert_matches!(
+            results[1],
+            ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
+        );
+    }
+}
+
+#[test]
+fn test_failed_tasks_do_not_abort_others() {
+    let requests = vec![
+        ExpansionRequest::Expand(ExpansionTask {
+            id: Some("unbalanced".to_string()),
+            ..test_task("id_macro", "struct S {")
+        }),
+        ExpansionRequest::Expand(ExpansionTask {
+            id: Some("unknown".to_string()),
+            ..test_task("unknown_macro", "struct S {}")
+        }),
+        ExpansionRequest::Expand(ExpansionTask {
+            id: Some("missing_lib".to_string()),
+            libs: vec![TEST_PROC_MACRO.with_file_name("missing_lib.so")],
+            ..test_task("id_macro", "struct S {}")
+        }),
+        ExpansionRequest::Expand(ExpansionTask {
+            id: Some("valid".to_string()),
+            ..test_task("id_macro", "struct S {}")
+        }),
+    ];
+
+    let results = perform_server_expansions(&requests).expect("Cannot perform server expansions");
+
+    assert_eq!(results.len(), 4);
+    assert_matches!(
+        results[0],
+        ExpansionResult::Error { code: ErrorCode::ParseError, .. }
+    );
+    assert_matches!(
+        results[1],
+        ExpansionResult::Error { code: ErrorCode::MacroNotFound, .. }
+    );
+    assert_matches!(
+        results[2],
+        ExpansionResult::Error { code: ErrorCode::LibraryNotFound, .. }
+    );
+    assert_matches!(
+        results[3],
+        ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
+    );
 }
 
+#[test]
+fn test_macro_panics_are_reported() {
+    let requests = vec![
+        ExpansionRequest::Expand(test_task("panicking_macro", "struct S {}")),
+        ExpansionRequest::Expand(test_task("id_macro", "struct S {}")),
+    ];
+
+    let results = perform_server_expansions(&requests).expect("Cannot perform server expansions");
+
+    assert_eq!(results.len(), 2);
+    assert_matches!(
+        results[0],
+        ExpansionResult::Error {
+            code: ErrorCode::MacroPanicked,
+            ref reason,
+            bridge_method: Some(ref method),
+            ..
+        } if reason.contains("panicking_macro is always panicking") && method == "ProcMacro::Bang"
+    );
+    assert_matches!(
+        results[1],
+        ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
+    );
+}
+
+#[test]
+fn test_isolated_workers_survive_crashes() {
+    let task = |macro_name: &str| ExpansionRequest::Expand(ExpansionTask {
+        id: Some(macro_name.to_string()),
+        ..test_task(macro_name, "struct S {}")
+    });
+
+    let requests = vec![task("id_macro"), task("exiting_macro"), task("id_macro")];
+
+    let results = perform_server_expansions_with_args(&requests, &["--isolate"])
+        .expect("Cannot perform server expansions");
+
+    assert_eq!(results.len(), 3);
+    assert_matches!(
+        results[0],
+        ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
+    );
+    assert_matches!(
+        results[1],
+        ExpansionResult::Error { code: ErrorCode::WorkerCrashed, ref id, .. }
+        if id.as_ref().map(|id| id.as_str()) == Some("exiting_macro")
+    );
+    assert_matches!(
+        results[2],
+        ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
+    );
+}
+
+#[test]
+fn test_timeouts() {
+    let task = |macro_name: &str, timeout_ms: Option<u64>| ExpansionRequest::Expand(ExpansionTask {
+        timeout_ms,
+        ..test_task(macro_name, "struct S {}")
+    });
+
+    let requests = vec![
+        task("looping_macro", Some(200)),
+        task("looping_macro", None),
+        task("id_macro", None),
+    ];
+
+    for args in &[vec!["--timeout-ms", "5000"], vec!["--timeout-ms", "5000", "--isolate"]] {
+        let results = perform_server_expansions_with_args(&requests, args)
+            .expect("Cannot perform server expansions");
+
+        assert_eq!(results.len(), 3);
+        assert_matches!(results[0], ExpansionResult::Error { code: ErrorCode::Timeout, .. });
+        assert_matches!(results[1], ExpansionResult::Error { code: ErrorCode::Timeout, .. });
+        assert_matches!(
+            results[2],
+            ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_resource_limits() {
+    let task = |macro_name: &str, limits: ResourceLimits| ExpansionRequest::Expand(ExpansionTask {
+        limits,
+        ..test_task(macro_name, "struct S {}")
+    });
+
+    let requests = vec![
+        task("looping_macro", ResourceLimits { cpu_seconds: Some(1), ..Default::default() }),
+        task("allocating_macro", ResourceLimits { memory_mb: Some(256), ..Default::default() }),
+        task("aborting_macro", ResourceLimits { memory_mb: Some(256), ..Default::default() }),
+        task("id_macro", ResourceLimits { memory_mb: Some(u64::max_value()), ..Default::default() }),
+        task("id_macro", ResourceLimits::default()),
+    ];
+
+    let results = perform_server_expansions_with_args(&requests, &["--isolate"])
+        .expect("Cannot perform server expansions");
+
+    assert_eq!(results.len(), 5);
+    assert_matches!(results[0], ExpansionResult::Error { code: ErrorCode::CpuLimitExceeded, .. });
+    assert_matches!(results[1], ExpansionResult::Error { code: ErrorCode::MemoryLimitExceeded, .. });
+    assert_matches!(results[2], ExpansionResult::Error { code: ErrorCode::WorkerCrashed, .. });
+    assert_matches!(results[3], ExpansionResult::Error { code: ErrorCode::InvalidRequest, .. });
+    assert_matches!(
+        results[4],
+        ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
+    );
+}
+
+#[test]
+fn test_libraries_are_cached_until_rebuilt() {
+    let tmp_dir = TempDir::new().expect("Cannot create temp dir");
+    setup_proc_macro_project(&tmp_dir.path()).expect("Cannot setup test project");
+    let proc_macro_dyn_lib = compile_proc_macro(&tmp_dir.path(), "test_proc_macro")
+        .expect("Cannot find proc macro!");
+
+    let mut server = Command::new(proc_macro_expander_exe().unwrap())
+        .arg("--server")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Cannot start server");
+
+    let mut stdin = server.stdin.take().unwrap();
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+
+    let mut expand = |macro_name: &str| -> ExpansionResult {
+        let request = ExpansionRequest::Expand(ExpansionTask {
+            libs: vec![proc_macro_dyn_lib.clone()],
+            ..test_task(macro_name, "")
+        });
+        writeln!(stdin, "{}", serde_json::to_string(&request).unwrap()).unwrap();
+
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    };
+
+    let count = |result: &ExpansionResult| match result {
+        ExpansionResult::Success { expansion, .. } => expansion.clone(),
+        _ => panic!("Expansion has failed: {:?}", result),
+    };
+
+    assert_eq!(count(&expand("counting_macro")), "const COUNT : usize = 1 ;");
+    assert_eq!(count(&expand("counting_macro")), "const COUNT : usize = 2 ;");
+    assert_matches!(expand("rebuilt_macro"), ExpansionResult::Error { code: ErrorCode::MacroNotFound, .. });
+
+    let mut lib_file = fs::OpenOptions::new()
+        .append(true)
+        .open(tmp_dir.path().join("src").join("lib.rs"))
+        .unwrap();
+    writeln!(
+        lib_file,
+        "{}",
+        r#"
+#[proc_macro]
+pub fn rebuilt_macro(input: TokenStream) -> TokenStream {
+    "const REBUILT: bool = true;".parse().unwrap()
+}
+        "#
+    ).unwrap();
+    compile_proc_macro(&tmp_dir.path(), "test_proc_macro").expect("Cannot find proc macro!");
+
+    assert_eq!(count(&expand("counting_macro")), "const COUNT : usize = 1 ;");
+    assert_eq!(count(&expand("rebuilt_macro")), "const REBUILT : bool = true ;");
+
+    drop(stdin);
+    server.wait().unwrap();
+}
+
+#[test]
+fn test_list_macros() {
+    let macros = list_macros(&[TEST_PROC_MACRO.clone()]).expect("Cannot list macros");
+    let find = |name: &str| macros.iter().find(|m| m.name == name).cloned();
+
+    assert!(find("HelperDerive").unwrap().has_helper_attribute("helper"));
+    assert!(!find("HelperDerive").unwrap().has_helper_attribute("serde"));
+
+    assert_eq!(
+        find("HelperDerive"),
+        Some(ExportedMacro {
+            kind: MacroKind::CustomDerive,
+            name: "HelperDerive".to_string(),
+            attributes: vec!["helper".to_string(), "other_helper".to_string()],
+            library: TEST_PROC_MACRO.clone(),
+        })
+    );
+
+    assert_eq!(
+        find("id_attribute"),
+        Some(ExportedMacro {
+            kind: MacroKind::Attr,
+            name: "id_attribute".to_string(),
+            attributes: vec![],
+            library: TEST_PROC_MACRO.clone(),
+        })
+    );
+
+    assert_eq!(
+        find("id_macro"),
+        Some(ExportedMacro {
+            kind: MacroKind::Bang,
+            name: "id_macro".to_string(),
+            attributes: vec![],
+            library: TEST_PROC_MACRO.clone(),
+        })
+    );
+}
+
+#[test]
+fn test_derive_input_preprocessing() {
+    let task = |cfg: Option<Vec<String>>| ExpansionTask {
+        cfg,
+        ..test_task("EchoInput", r#"
+            #[derive(Debug, EchoInput)]
+            #[cfg_attr(unix, doc = "unix_doc")]
+            #[cfg_attr(feature = "serde", doc = "serde_doc")]
+            enum E {
+                A { #[cfg(all(unix, not(windows)))] unix_field: i32, #[cfg(windows)] windows_field: i32 },
+                #[cfg(feature = "serde")]
+                B,
+            }
+        "#)
+    };
+
+    let unprocessed = perform_expansion(task(None)).expect("Cannot perform expansion");
+    assert_matches!(
+        unprocessed,
+        ExpansionResult::Success { ref expansion, .. }
+        if expansion.contains("derive") && expansion.contains("windows_field")
+    );
+
+    let processed = perform_expansion(task(Some(vec!["unix".to_string()])))
+        .expect("Cannot perform expansion");
+    match processed {
+        ExpansionResult::Success { ref expansion, .. } => {
+            assert!(!expansion.contains("derive"));
+            assert!(!expansion.contains("cfg"));
+            assert!(expansion.contains("unix_doc") && !expansion.contains("serde_doc"));
+            assert!(expansion.contains("unix_field") && !expansion.contains("windows_field"));
+            assert!(!expansion.contains(" B"));
+        }
+        _ => panic!("Expansion has failed: {:?}", processed),
+    }
+
+    let with_feature = perform_expansion(task(Some(vec!["feature=\"serde\"".to_string()])))
+        .expect("Cannot perform expansion");
+    assert_matches!(
+        with_feature,
+        ExpansionResult::Success { ref expansion, .. }
+        if expansion.contains("serde_doc") && expansion.contains(" B") && !expansion.contains("unix_field")
+    );
+
+    let invalid_cfg = perform_expansion(task(Some(vec!["feature=serde".to_string()])))
+        .expect("Cannot perform expansion");
+    assert_matches!(invalid_cfg, ExpansionResult::Error { code: ErrorCode::InvalidRequest, .. });
+}
+
+#[test]
+fn test_spans_of_located_input() {
+    let task = |source: Option<SourceLocation>| ExpansionTask {
+        source,
+        ..test_task("span_positions_macro", "foo (bar)\n  \"ж\" baz")
+    };
+
+    let located = perform_expansion(task(Some(SourceLocation {
+        file: PathBuf::from("/project/src/lib.rs"),
+        offset: 120,
+        line: 5,
+        column: 8,
+    }))).expect("Cannot perform expansion");
+
+    assert_matches!(
+        located,
+        ExpansionResult::Success { ref expansion, .. }
+        if expansion == r#""/project/src/lib.rs:5:8-5:11 /project/src/lib.rs:5:12-5:17 /project/src/lib.rs:6:2-6:5 /project/src/lib.rs:6:6-6:9""#
+    );
+
+    let unlocated = perform_expansion(task(None)).expect("Cannot perform expansion");
+
+    assert_matches!(
+        unlocated,
+        ExpansionResult::Success { ref expansion, .. }
+        if expansion == r#""<macro_body>:1:0-1:3 <macro_body>:1:4-1:9 <macro_body>:2:2-2:5 <macro_body>:2:6-2:9""#
+    );
+
+    let attribute_task = |attributes_source: Option<SourceLocation>| ExpansionTask {
+        attributes: Some("inline(always)\n x".to_string()),
+        attributes_source,
+        ..test_task("span_positions_attribute", "struct S;")
+    };
+
+    let located_attributes = perform_expansion(attribute_task(Some(SourceLocation {
+        file: PathBuf::from("/project/src/lib.rs"),
+        offset: 90,
+        line: 4,
+        column: 2,
+    }))).expect("Cannot perform expansion");
+
+    assert_matches!(
+        located_attributes,
+        ExpansionResult::Success { ref expansion, .. }
+        if expansion == r#""/project/src/lib.rs:4:2-4:8 /project/src/lib.rs:4:8-4:16 /project/src/lib.rs:5:1-5:2""#
+    );
+
+    let unlocated_attributes = perform_expansion(attribute_task(None)).expect("Cannot perform expansion");
+
+    assert_matches!(
+        unlocated_attributes,
+        ExpansionResult::Success { ref expansion, .. }
+        if expansion == r#""<attributes>:1:0-1:6 <attributes>:1:6-1:14 <attributes>:2:1-2:2""#
+    );
+}
+
+#[test]
+fn test_span_map() {
+    let task = ExpansionTask {
+        source: Some(SourceLocation {
+            file: PathBuf::from("/project/src/lib.rs"),
+            offset: 100,
+            line: 3,
+            column: 0,
+        }),
+        span_map: true,
+        ..test_task("site_spans_macro", "x = (1)")
+    };
+
+    let result = perform_expansion(task).expect("Cannot perform expansion");
+    let part = match result {
+        ExpansionResult::Success { ref parts, .. } => parts[0].clone(),
+        _ => panic!("Expansion has failed: {:?}", result),
+    };
+
+    assert_eq!(part.expansion, "let x = ( 1 ) def");
+
+    let input = |start, end| TokenSource::Input { input: InputKind::MacroBody, start, end };
+    let token = |start, end, source| TokenSpan { start, end, source, hygiene: Hygiene::CallSite };
+
+    assert_eq!(
+        part.tokens,
+        Some(vec![
+            token(0, 3, TokenSource::CallSite),
+            token(4, 5, input(100, 101)),
+            token(6, 7, input(102, 103)),
+            token(8, 9, input(104, 105)),
+            token(10, 11, input(105, 106)),
+            token(12, 13, input(106, 107)),
+            TokenSpan { hygiene: Hygiene::DefSite, ..token(14, 17, TokenSource::DefSite) },
+        ])
+    );
+}
+
+#[test]
+fn test_hygiene() {
+    let task = ExpansionTask {
+        span_map: true,
+        ..test_task("hygiene_macro", "x")
+    };
+
+    let result = perform_expansion(task).expect("Cannot perform expansion");
+    let part = match result {
+        ExpansionResult::Success { ref parts, .. } => parts[0].clone(),
+        _ => panic!("Expansion has failed: {:?}", result),
+    };
+
+    assert_eq!(part.expansion, "mixed def call resolved");
+
+    let token = |start, end, source, hygiene| TokenSpan { start, end, source, hygiene };
+
+    assert_eq!(
+        part.tokens,
+        Some(vec![
+            token(0, 5, TokenSource::MixedSite, Hygiene::MixedSite),
+            token(6, 9, TokenSource::DefSite, Hygiene::DefSite),
+            token(10, 14, TokenSource::CallSite, Hygiene::CallSite),
+            token(
+                15,
+                23,
+                TokenSource::Input { input: InputKind::MacroBody, start: 0, end: 1 },
+                Hygiene::DefSite,
+            ),
+        ])
+    );
+}
+
+#[test]
+fn test_numeric_literals() {
+    let task = test_task("numeric_literals_macro", "");
+
+    let result = perform_expansion(task).expect("Cannot perform expansion");
+    assert_matches!(
+        result,
+        ExpansionResult::Success { ref expansion, .. } if expansion == &[
+            "-1isize",
+            "7",
+            "340282366920938463463374607431768211455u128",
+            "-170141183460469231731687303715884105728i128",
+            "-128",
+            "10000000000f32",
+            "0.25",
+            "-2.5f64",
+        ].join(" ")
+    );
+}
+
+#[test]
+fn test_identifiers() {
+    let task = |macro_name: &str| test_task(macro_name, "r#type x");
+
+    let result = perform_expansion(task("raw_ident_macro")).expect("Cannot perform expansion");
+    assert_matches!(
+        result,
+        ExpansionResult::Success { ref expansion, .. } if expansion == "r#type x r#match fn"
+    );
+
+    let result = perform_expansion(task("invalid_ident_macro")).expect("Cannot perform expansion");
+    assert_matches!(
+        result,
+        ExpansionResult::Error {
+            code: ErrorCode::InvalidBridgeCall,
+            ref reason,
+            bridge_method: Some(ref method),
+            ..
+        } if reason.contains("'not an ident' is not a valid identifier") && method == "Ident::new"
+    );
+}
+
+#[test]
+fn test_invalid_token_stream_text() {
+    let task = |macro_name: &str| test_task(macro_name, "struct S {}");
+
+    let requests = vec![
+        ExpansionRequest::Expand(task("invalid_parse_macro")),
+        ExpansionRequest::Expand(task("id_macro")),
+    ];
+
+    let results = perform_server_expansions(&requests).expect("Cannot perform server expansions");
+
+    assert_eq!(results.len(), 2);
+    assert_matches!(
+        results[0],
+        ExpansionResult::Error {
+            code: ErrorCode::InvalidBridgeCall,
+            ref reason,
+            bridge_method: Some(ref method),
+            ..
+        } if reason.contains("cannot parse 'fn ('") && method == "TokenStream::from_str"
+    );
+    assert_matches!(
+        results[1],
+        ExpansionResult::Success { ref expansion, .. } if expansion.contains("struct S")
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_memory_is_flat_across_expansions() {
+    let task = test_task("id_macro", &"x + 1; ".repeat(2000));
+    let request = serde_json::to_string(&ExpansionRequest::Expand(task)).unwrap();
+
+    let mut server = Command::new(proc_macro_expander_exe().unwrap())
+        .arg("--server")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Cannot start server");
+
+    let mut stdin = server.stdin.take().unwrap();
+    let mut results = BufReader::new(server.stdout.take().unwrap()).lines();
+    let mut expand = |count| {
+        for _ in 0..count {
+            writeln!(stdin, "{}", request).unwrap();
+            let line = results.next().expect("Server has stopped").unwrap();
+            let result: ExpansionResult = serde_json::from_str(&line).unwrap();
+            assert_matches!(result, ExpansionResult::Success { .. });
+        }
+    };
+
+    expand(200);
+    let warmed_up = resident_memory_kb(server.id()).expect("Cannot get server memory");
+    expand(2000);
+    let finished = resident_memory_kb(server.id()).expect("Cannot get server memory");
+
+    drop(expand);
+    drop(stdin);
+    server.wait().expect("Server has not finished");
+
+    assert!(
+        finished < warmed_up + 8 * 1024,
+        "Server memory has grown from {} KB to {} KB",
+        warmed_up,
+        finished
+    );
+}
+
+#[test]
+fn test_diagnostics() {
+    let task = |macro_name: &str| test_task(macro_name, "struct S {}");
+
+    let diagnostic = |level, message: &str, spans| Diagnostic {
+        level,
+        message: message.to_string(),
+        spans,
+        children: vec![],
+    };
+
+    let success = perform_expansion(task("diagnostics_macro")).expect("Cannot perform expansion");
+    assert_matches!(
+        success,
+        ExpansionResult::Success { ref diagnostics, .. } if diagnostics == &vec![
+            diagnostic(
+                DiagnosticLevel::Warning,
+                "first token",
+                vec![TokenSource::Input { input: InputKind::MacroBody, start: 0, end: 6 }],
+            ),
+            diagnostic(DiagnosticLevel::Error, "whole input", vec![TokenSource::CallSite]),
+        ]
+    );
+
+    let error = perform_expansion(task("diagnostics_panicking_macro"))
+        .expect("Cannot perform expansion");
+    assert_matches!(
+        error,
+        ExpansionResult::Error { code: ErrorCode::MacroPanicked, ref diagnostics, .. } if diagnostics == &vec![
+            diagnostic(DiagnosticLevel::Error, "about to panic", vec![TokenSource::CallSite]),
+        ]
+    );
+}
+
+#[test]
+fn test_diagnostic_tree() {
+    let task = test_task("diagnostic_tree_macro", "struct S {}");
+
+    let body_span = |start, end| TokenSource::Input { input: InputKind::MacroBody, start, end };
+
+    let result = perform_expansion(task).expect("Cannot perform expansion");
+    assert_matches!(
+        result,
+        ExpansionResult::Success { ref diagnostics, .. } if diagnostics == &vec![Diagnostic {
+            level: DiagnosticLevel::Error,
+            message: "multi-span error".to_string(),
+            spans: vec![body_span(0, 6), body_span(7, 8)],
+            children: vec![
+                Diagnostic {
+                    level: DiagnosticLevel::Note,
+                    message: "note on the second token".to_string(),
+                    spans: vec![body_span(7, 8)],
+                    children: vec![],
+                },
+                Diagnostic {
+                    level: DiagnosticLevel::Help,
+                    message: "help without spans".to_string(),
+                    spans: vec![],
+                    children: vec![],
+                },
+            ],
+        }]
+    );
+}
+
+#[test]
+fn test_compile_errors() {
+    let task = |compile_errors| ExpansionTask {
+        compile_errors,
+        ..test_task("compile_error_macro", "struct S {}")
+    };
+
+    let result = perform_expansion(task(false)).expect("Cannot perform expansion");
+    assert_matches!(
+        result,
+        ExpansionResult::Success { ref expansion, ref diagnostics, .. }
+            if expansion == r#"compile_error ! ( "expected \"fn\"" ) ;"# && diagnostics.is_empty()
+    );
+
+    let result = perform_expansion(task(true)).expect("Cannot perform expansion");
+    assert_matches!(
+        result,
+        ExpansionResult::Success { ref expansion, ref diagnostics, .. }
+            if expansion == r#"compile_error ! ( "expected \"fn\"" ) ;"# && diagnostics == &vec![Diagnostic {
+                level: DiagnosticLevel::Error,
+                message: r#"expected "fn""#.to_string(),
+                spans: vec![TokenSource::Input { input: InputKind::MacroBody, start: 0, end: 6 }],
+                children: vec![],
+            }]
+    );
+}
+
+#[test]
+fn test_literal_subspans() {
+    let task = test_task("literal_subspan_macro", r##""a\"x" b"\x00x" r#"x"# 'x' "éx""##);
+
+    let x = |start, end| Diagnostic {
+        level: DiagnosticLevel::Warning,
+        message: "x".to_string(),
+        spans: vec![TokenSource::Input { input: InputKind::MacroBody, start, end }],
+        children: vec![],
+    };
+
+    let result = perform_expansion(task).expect("Cannot perform expansion");
+    assert_matches!(
+        result,
+        ExpansionResult::Success { ref diagnostics, .. } if diagnostics == &vec![
+            x(4, 5),
+            x(13, 14),
+            x(19, 20),
+            x(24, 25),
+            x(30, 31),
+        ]
+    );
+}
+
+#[test]
+fn test_source_text() {
+    let task = test_task("source_text_macro", "a  +\n b");
+
+    let result = perform_expansion(task).expect("Cannot perform expansion");
+    assert_matches!(
+        result,
+        ExpansionResult::Success { ref expansion, .. }
+            if expansion == r#""a" "+" "b" "<none>" "<none>" "a  +\n b""#
+    );
+}
+
+/// Linear congruential generator, which makes the generated inputs reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        (self.0 >> 33) as usize % bound
+    }
+}
+
+/// Appends pseudo-random tokens separated with arbitrary whitespace to `out`.
+fn random_tokens(rng: &mut Rng, depth: usize, out: &mut String) {
+    const TOKENS: &[&str] = &[
+        "foo", "r#type", "_x", "é", "+", "::", "->", "#", r#""a\"b""#, "1u8", "'c'", "b\"x\"",
+        r##"r#"raw"#"##, "1.5e3",
+    ];
+    const WHITESPACE: &[&str] = &[" ", "  ", "\n", "\n\t "];
+    const DELIMITERS: &[(&str, &str)] = &[("(", ")"), ("[", "]"), ("{", "}")];
+
+    for _ in 0..rng.below(8) {
+        if depth > 0 && rng.below(4) == 0 {
+            let (open, close) = DELIMITERS[rng.below(DELIMITERS.len())];
+            out.push_str(open);
+            random_tokens(rng, depth - 1, out);
+            out.push_str(close);
+        } else {
+            out.push_str(TOKENS[rng.below(TOKENS.len())]);
+        }
+
+        out.push_str(WHITESPACE[rng.below(WHITESPACE.len())]);
+    }
+}
+
+#[test]
+fn test_spans_round_trip() {
+    let inputs: Vec<String> = (0..50)
+        .map(|seed| {
+            let mut input = String::new();
+            random_tokens(&mut Rng(seed), 3, &mut input);
+            input
+        })
+        .collect();
+
+    let task = |macro_name: &str, input: &str| ExpansionRequest::Expand(ExpansionTask {
+        span_map: true,
+        ..test_task(macro_name, input)
+    });
+
+    let requests: Vec<ExpansionRequest> = inputs
+        .iter()
+        .flat_map(|input| vec![task("id_macro", input), task("respan_macro", input)])
+        .collect();
+
+    let results = perform_server_expansions(&requests).expect("Cannot perform server expansions");
+    assert_eq!(results.len(), requests.len());
+
+    for (input, results) in inputs.iter().zip(results.chunks(2)) {
+        let (id, respanned) = match (&results[0], &results[1]) {
+            (
+                ExpansionResult::Success { parts: id, .. },
+                ExpansionResult::Success { parts: respanned, diagnostics, .. },
+            ) if diagnostics.is_empty() => (&id[0], &respanned[0]),
+            _ => panic!("Expansion of {:?} has failed: {:?}", input, results),
+        };
+
+        // Recreated tokens are indistinguishable from the original ones
+        assert_eq!(respanned.expansion, id.expansion, "Input: {:?}", input);
+        assert_eq!(respanned.tokens, id.tokens, "Input: {:?}", input);
+
+        // Each token points to the exact text it has been parsed from
+        for token in respanned.tokens.as_ref().unwrap() {
+            match token.source {
+                TokenSource::Input { input: InputKind::MacroBody, start, end } => assert_eq!(
+                    &input[start..end],
+                    &respanned.expansion[token.start..token.end],
+                    "Input: {:?}",
+                    input
+                ),
+                ref source => panic!("Unexpected source {:?} of a token of {:?}", source, input),
+            }
+            assert_eq!(token.hygiene, Hygiene::CallSite);
+        }
+    }
+}